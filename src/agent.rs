@@ -1,69 +1,285 @@
-use image::ImageFormat;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use image::{Delay, DynamicImage, Frame, GenericImageView};
+use js_sys::{Array, Uint8Array};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use yew_agent::{HandlerId, Public, WorkerLink};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent};
+use yew::Callback;
 
-use crate::img::{get_orientation, sort_img, ImageToBytes, SortSettings};
+use crate::animation::{load_animation, AnimationEncoder, AnimationFormat, AnimationSettings};
+use crate::img::{
+    encode_img, load_img, preview_img, sort_img, OutputFormat, Preview, SortSettings,
+};
+use crate::BlobUrl;
 
+/// The worker's end of the channel, set up by [`Worker::register`]
 pub struct Worker {
-    link: WorkerLink<Self>,
+    scope: DedicatedWorkerGlobalScope,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct WorkerInput {
+    #[serde(skip)]
     pub img_data: Vec<u8>,
     pub settings: SortSettings,
+    /// Animate a still image, or vary the settings over the frames of an animated image
+    pub animation: Option<AnimationSettings>,
+    /// Only applies to still images
+    pub preview: Preview,
+    /// Send the pixels back as they are, to be drawn straight to a canvas, rather than encoded.
+    /// Only applies to still images
+    pub raw: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum WorkerStatus {
     Decoding,
     Sorting,
+    /// Frame number (starting from 1) and total frames of an animation
+    SortingFrame(usize, usize),
     Encoding,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SortedImage {
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    pub format: OutputFormat,
+}
+
+/// Unencoded 8-bit RGBA pixels
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RawImage {
+    pub width: u32,
+    pub height: u32,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum WorkerOutput {
     StatusUpdate(WorkerStatus),
-    Sorted(Vec<u8>),
+    Sorted(SortedImage),
+    Rendered(RawImage),
+    Failed(String),
+}
+
+/// A message to or from the worker. Its image data is sent as a buffer of its own, which is
+/// transferred rather than copied, and the rest as JSON
+trait Message: Serialize + DeserializeOwned {
+    fn data_mut(&mut self) -> Option<&mut Vec<u8>>;
+}
+
+impl Message for WorkerInput {
+    fn data_mut(&mut self) -> Option<&mut Vec<u8>> {
+        Some(&mut self.img_data)
+    }
+}
+
+impl Message for WorkerOutput {
+    fn data_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            WorkerOutput::Sorted(img) => Some(&mut img.data),
+            WorkerOutput::Rendered(img) => Some(&mut img.data),
+            WorkerOutput::StatusUpdate(_) | WorkerOutput::Failed(_) => None,
+        }
+    }
+}
+
+/// Split a message into what to post and the buffers to transfer with it. The data is copied
+/// out of wasm memory once, and the buffer is then moved to the other side rather than cloned
+fn pack(mut msg: impl Message) -> (Array, Array) {
+    let data = msg.data_mut().map(std::mem::take).unwrap_or_default();
+    let header = serde_json::to_string(&msg).unwrap_throw();
+    let buffer = Uint8Array::from(data.as_slice()).buffer();
+    (Array::of2(&header.into(), &buffer), Array::of1(&buffer))
+}
+
+fn unpack<M: Message>(packed: JsValue) -> M {
+    let packed: Array = packed.unchecked_into();
+    let header = packed.get(0).as_string().unwrap_throw();
+    let mut msg: M = serde_json::from_str(&header).unwrap_throw();
+    if let Some(data) = msg.data_mut() {
+        *data = Uint8Array::new(&packed.get(1)).to_vec();
+    }
+    msg
+}
+
+/// The app's end of the channel to the worker
+pub struct WorkerBridge {
+    worker: web_sys::Worker,
+    /// Work sent before the worker has loaded, which it would otherwise miss. `None` once it has
+    pending: Rc<RefCell<Option<Vec<WorkerInput>>>>,
+    _script: BlobUrl,
+    _onmessage: Closure<dyn Fn(MessageEvent)>,
+}
+
+impl WorkerBridge {
+    pub fn new(callback: Callback<WorkerOutput>) -> Self {
+        // Trunk builds the worker as a script that sets up its wasm when asked
+        let origin = gloo::utils::document()
+            .location()
+            .unwrap_throw()
+            .origin()
+            .unwrap_throw();
+        let script = format!(
+            r#"importScripts("{origin}/worker.js");wasm_bindgen("{origin}/worker_bg.wasm");"#
+        );
+        let script = BlobUrl::new(script.as_bytes(), "application/javascript");
+        let worker = web_sys::Worker::new(&script).unwrap_throw();
+
+        let pending = Rc::new(RefCell::new(Some(vec![])));
+        let onmessage = {
+            let worker = worker.clone();
+            let pending = pending.clone();
+            Closure::<dyn Fn(MessageEvent)>::new(move |e: MessageEvent| {
+                // The worker posts nothing once it has loaded
+                if e.data().is_null() {
+                    for input in pending.take().unwrap_or_default() {
+                        post(&worker, input);
+                    }
+                } else {
+                    callback.emit(unpack(e.data()));
+                }
+            })
+        };
+        worker.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+
+        Self {
+            worker,
+            pending,
+            _script: script,
+            _onmessage: onmessage,
+        }
+    }
+
+    pub fn send(&self, input: WorkerInput) {
+        match self.pending.borrow_mut().as_mut() {
+            Some(pending) => pending.push(input),
+            None => post(&self.worker, input),
+        }
+    }
 }
 
-impl yew_agent::Worker for Worker {
-    type Reach = Public<Self>;
-    type Message = ();
-    type Input = WorkerInput;
-    type Output = WorkerOutput;
+impl Drop for WorkerBridge {
+    fn drop(&mut self) {
+        self.worker.terminate();
+    }
+}
 
-    fn create(link: WorkerLink<Self>) -> Self {
-        Self { link }
+fn post(worker: &web_sys::Worker, input: WorkerInput) {
+    let (msg, transfer) = pack(input);
+    worker
+        .post_message_with_transfer(&msg, &transfer)
+        .unwrap_throw();
+}
+
+impl Worker {
+    /// Answer work sent by [`WorkerBridge`]. Called from the worker's own script
+    pub fn register() {
+        let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+        let worker = Worker {
+            scope: scope.clone(),
+        };
+        let onmessage = Closure::<dyn Fn(MessageEvent)>::new(move |e: MessageEvent| {
+            worker.handle_input(unpack(e.data()))
+        });
+        scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        onmessage.forget();
+        scope.post_message(&JsValue::NULL).unwrap_throw();
     }
 
-    fn update(&mut self, _msg: Self::Message) {
-        // no messaging
+    fn respond(&self, output: WorkerOutput) {
+        let (msg, transfer) = pack(output);
+        self.scope
+            .post_message_with_transfer(&msg, &transfer)
+            .unwrap_throw();
     }
 
-    fn handle_input(&mut self, msg: Self::Input, id: HandlerId) {
-        self.link
-            .respond(id, WorkerOutput::StatusUpdate(WorkerStatus::Decoding));
-        let mut img = image::load_from_memory(msg.img_data.as_slice()).unwrap();
-        if let Some(orientation) = get_orientation(&msg.img_data) {
-            img = match orientation {
-                3 => img.rotate180(),
-                6 => img.rotate90(),
-                8 => img.rotate270(),
-                _ => img,
-            };
+    fn handle_input(&self, msg: WorkerInput) {
+        self.respond(WorkerOutput::StatusUpdate(WorkerStatus::Decoding));
+        if let Some(frames) = load_animation(&msg.img_data) {
+            let dimensions = frames[0].buffer().dimensions();
+            let animation = msg.animation.unwrap_or_else(|| AnimationSettings {
+                format: AnimationFormat::for_source(&msg.img_data),
+                ..AnimationSettings::default()
+            });
+            self.sort_animation(frames.into_iter(), dimensions, &msg.settings, &animation);
+            return;
+        }
+        let img = match load_img(&msg.img_data) {
+            Ok(img) => img,
+            Err(err) => {
+                self.respond(WorkerOutput::Failed(err.to_string()));
+                return;
+            }
+        };
+
+        if let Some(animation) = &msg.animation {
+            let still = img.to_rgba8();
+            let delay = Delay::from_numer_denom_ms(animation.frame_delay_ms, 1);
+            let frames =
+                (0..animation.frame_count).map(|_| Frame::from_parts(still.clone(), 0, 0, delay));
+            self.sort_animation(frames, img.dimensions(), &msg.settings, animation);
+            return;
         }
 
-        self.link
-            .respond(id, WorkerOutput::StatusUpdate(WorkerStatus::Sorting));
-        let img = sort_img(img, msg.settings);
-        self.link
-            .respond(id, WorkerOutput::StatusUpdate(WorkerStatus::Encoding));
-        let sorted = img.to_bytes(ImageFormat::Jpeg);
-        self.link.respond(id, WorkerOutput::Sorted(sorted))
+        self.respond(WorkerOutput::StatusUpdate(WorkerStatus::Sorting));
+        // Chosen from the source, as every 8-bit image is sorted as RGBA
+        let format = OutputFormat::for_image(&img);
+        let img = preview_img(img, msg.settings, &msg.preview);
+        if msg.raw {
+            let (width, height) = img.dimensions();
+            let data = img.into_rgba8().into_raw();
+            self.respond(WorkerOutput::Rendered(RawImage {
+                width,
+                height,
+                data,
+            }));
+            return;
+        }
+        self.respond(WorkerOutput::StatusUpdate(WorkerStatus::Encoding));
+        match encode_img(&img, format.image_format().unwrap()) {
+            Ok(data) => self.respond(WorkerOutput::Sorted(SortedImage { data, format })),
+            Err(err) => self.respond(WorkerOutput::Failed(err.to_string())),
+        }
     }
 
-    fn name_of_resource() -> &'static str {
-        "worker.js"
+    /// Sort each frame with the settings from `animation`'s keyframes, falling back to `settings`
+    fn sort_animation(
+        &self,
+        frames: impl ExactSizeIterator<Item = Frame>,
+        dimensions: (u32, u32),
+        settings: &SortSettings,
+        animation: &AnimationSettings,
+    ) {
+        let total = frames.len();
+        let mut data = vec![];
+        let mut encoder =
+            AnimationEncoder::new(&mut data, &animation.format, dimensions, total as u32);
+        for (i, frame) in frames.enumerate() {
+            self.respond(WorkerOutput::StatusUpdate(WorkerStatus::SortingFrame(
+                i + 1,
+                total,
+            )));
+            let frame_settings = animation
+                .settings_at(i as u32 + 1)
+                .unwrap_or_else(|| settings.clone());
+            let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+            let img = DynamicImage::ImageRgba8(frame.into_buffer());
+            let sorted = sort_img(img, frame_settings).into_rgba8();
+            encoder.add_frame(Frame::from_parts(sorted, left, top, delay));
+        }
+
+        self.respond(WorkerOutput::StatusUpdate(WorkerStatus::Encoding));
+        encoder.finish(animation);
+        self.respond(WorkerOutput::Sorted(SortedImage {
+            data,
+            format: animation.format.output_format(),
+        }))
     }
 }
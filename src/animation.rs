@@ -0,0 +1,180 @@
+use std::io::Cursor;
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Frame, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::archive::ZipBuilder;
+use crate::img::{ImageToBytes, OutputFormat, SortSettings};
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum AnimationFormat {
+    #[default]
+    Gif,
+    Apng,
+    PngSequence,
+}
+
+impl AnimationFormat {
+    pub fn output_format(&self) -> OutputFormat {
+        match self {
+            AnimationFormat::Gif => OutputFormat::Gif,
+            AnimationFormat::Apng => OutputFormat::Png,
+            AnimationFormat::PngSequence => OutputFormat::Zip,
+        }
+    }
+
+    /// Same container as an animated source image. There's no WebP encoder, so WebP becomes a GIF.
+    pub fn for_source(img_data: &[u8]) -> AnimationFormat {
+        match image::guess_format(img_data) {
+            Ok(ImageFormat::Png) => AnimationFormat::Apng,
+            _ => AnimationFormat::Gif,
+        }
+    }
+}
+
+/// Settings to use at a given frame, starting from 1
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Keyframe {
+    pub frame: u32,
+    pub settings: SortSettings,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct AnimationSettings {
+    /// Kept ordered by frame
+    pub keyframes: Vec<Keyframe>,
+    /// Only used when animating a still image, animations keep their own frames and delays
+    pub frame_count: u32,
+    pub frame_delay_ms: u32,
+    pub format: AnimationFormat,
+}
+
+impl Default for AnimationSettings {
+    fn default() -> Self {
+        AnimationSettings {
+            keyframes: vec![],
+            frame_count: 60,
+            frame_delay_ms: 40,
+            format: AnimationFormat::Gif,
+        }
+    }
+}
+
+impl AnimationSettings {
+    /// Add a keyframe, replacing any existing keyframe at the same frame
+    pub fn set_keyframe(&mut self, keyframe: Keyframe) {
+        match self
+            .keyframes
+            .binary_search_by_key(&keyframe.frame, |k| k.frame)
+        {
+            Ok(i) => self.keyframes[i] = keyframe,
+            Err(i) => self.keyframes.insert(i, keyframe),
+        }
+    }
+
+    /// Settings at `frame`, interpolated between the surrounding keyframes. Frames before the first
+    /// or after the last keyframe hold its settings. `None` if there are no keyframes.
+    pub fn settings_at(&self, frame: u32) -> Option<SortSettings> {
+        let next = self.keyframes.iter().position(|k| k.frame >= frame);
+        match next {
+            Some(0) => self.keyframes.first().map(|k| k.settings.clone()),
+            Some(i) => {
+                let (a, b) = (&self.keyframes[i - 1], &self.keyframes[i]);
+                let t = (frame - a.frame) as f32 / (b.frame - a.frame) as f32;
+                Some(a.settings.lerp(&b.settings, t))
+            }
+            None => self.keyframes.last().map(|k| k.settings.clone()),
+        }
+    }
+}
+
+/// Decode every frame of an animated GIF, APNG or WebP, or `None` if the image isn't animated
+pub fn load_animation(img_data: &[u8]) -> Option<Vec<Frame>> {
+    let cursor = Cursor::new(img_data);
+    let frames = match image::guess_format(img_data).ok()? {
+        ImageFormat::Gif => GifDecoder::new(cursor).ok()?.into_frames(),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(cursor).ok()?;
+            if !decoder.is_apng() {
+                return None;
+            }
+            decoder.apng().into_frames()
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(cursor).ok()?;
+            if !decoder.has_animation() {
+                return None;
+            }
+            decoder.into_frames()
+        }
+        _ => return None,
+    };
+    let frames = frames.collect_frames().ok()?;
+    (frames.len() > 1).then_some(frames)
+}
+
+/// Encodes frames one at a time, so a whole animation never has to be held in memory.
+/// Frames are expected to cover the full canvas.
+pub enum AnimationEncoder<'a> {
+    Gif(GifEncoder<&'a mut Vec<u8>>),
+    Apng(png::Writer<&'a mut Vec<u8>>),
+    PngSequence(ZipBuilder<Cursor<&'a mut Vec<u8>>>, u32),
+}
+
+impl<'a> AnimationEncoder<'a> {
+    pub fn new(
+        buf: &'a mut Vec<u8>,
+        format: &AnimationFormat,
+        (width, height): (u32, u32),
+        frame_count: u32,
+    ) -> Self {
+        match format {
+            AnimationFormat::Gif => {
+                let mut encoder = GifEncoder::new_with_speed(buf, 10);
+                encoder.set_repeat(Repeat::Infinite).unwrap();
+                AnimationEncoder::Gif(encoder)
+            }
+            AnimationFormat::Apng => {
+                let mut encoder = png::Encoder::new(buf, width, height);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(frame_count, 0).unwrap();
+                AnimationEncoder::Apng(encoder.write_header().unwrap())
+            }
+            AnimationFormat::PngSequence => {
+                AnimationEncoder::PngSequence(ZipBuilder::new(Cursor::new(buf)), 0)
+            }
+        }
+    }
+
+    pub fn add_frame(&mut self, frame: Frame) {
+        match self {
+            AnimationEncoder::Gif(encoder) => encoder.encode_frame(frame).unwrap(),
+            AnimationEncoder::Apng(writer) => {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                writer
+                    .set_frame_delay((numer / denom.max(1)) as u16, 1000)
+                    .unwrap();
+                writer.write_image_data(frame.buffer().as_raw()).unwrap();
+            }
+            AnimationEncoder::PngSequence(zip, count) => {
+                *count += 1;
+                let data = frame.into_buffer().to_bytes(ImageFormat::Png);
+                zip.add_file(&format!("frame_{:04}.png", count), None, &data);
+            }
+        }
+    }
+
+    pub fn finish(self, settings: &AnimationSettings) {
+        match self {
+            AnimationEncoder::Gif(_) => {}
+            AnimationEncoder::Apng(writer) => writer.finish().unwrap(),
+            AnimationEncoder::PngSequence(zip, _) => {
+                zip.finish(settings);
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+use std::io::{Cursor, Seek, Write};
+use std::path::PathBuf;
+
+use serde::Serialize;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Serialize)]
+pub struct Manifest<'a, S: Serialize> {
+    pub settings: &'a S,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize)]
+pub struct ManifestEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub output: String,
+}
+
+/// Writes files into a zip one at a time, finishing with a manifest of the settings used.
+///
+/// Clashing file names are given a numeric suffix so nothing gets overwritten when extracted.
+pub struct ZipBuilder<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    used_names: HashSet<String>,
+    entries: Vec<ManifestEntry>,
+}
+
+impl<W: Write + Seek> ZipBuilder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            zip: ZipWriter::new(writer),
+            used_names: HashSet::from([MANIFEST_NAME.to_string()]),
+            entries: vec![],
+        }
+    }
+
+    pub fn add_file(&mut self, name: &str, source: Option<String>, data: &[u8]) {
+        let name = self.unique_name(name);
+        self.zip.start_file(name.as_str(), Self::options()).unwrap();
+        self.zip.write_all(data).unwrap();
+        self.entries.push(ManifestEntry {
+            source,
+            output: name,
+        });
+    }
+
+    pub fn finish(mut self, settings: &impl Serialize) -> W {
+        let manifest = Manifest {
+            settings,
+            files: self.entries,
+        };
+        self.zip.start_file(MANIFEST_NAME, Self::options()).unwrap();
+        serde_json::to_writer_pretty(&mut self.zip, &manifest).unwrap();
+        self.zip.finish().unwrap()
+    }
+
+    fn options() -> FileOptions {
+        // Images are already compressed, so deflating them again is just wasted time
+        FileOptions::default().compression_method(CompressionMethod::Stored)
+    }
+
+    fn unique_name(&mut self, name: &str) -> String {
+        let path = PathBuf::from(name);
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let mut candidate = name.to_string();
+        let mut n = 2;
+        while self.used_names.contains(&candidate) {
+            candidate = match extension {
+                Some(ext) => format!("{}_{}.{}", stem, n, ext),
+                None => format!("{}_{}", stem, n),
+            };
+            n += 1;
+        }
+        self.used_names.insert(candidate.clone());
+        candidate
+    }
+}
+
+/// Bundle sorted images into a zip along with a manifest of the settings used.
+///
+/// Each entry is `(output file name, source file name, data)`.
+pub fn create_zip(settings: &impl Serialize, outputs: &[(String, String, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipBuilder::new(Cursor::new(vec![]));
+    for (output_name, source_name, data) in outputs {
+        zip.add_file(output_name, Some(source_name.clone()), data);
+    }
+    zip.finish(settings).into_inner()
+}
@@ -0,0 +1,241 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use image::{DynamicImage, ImageError, ImageFormat};
+use pixel_sorter::animation::{AnimationSettings, Keyframe};
+use pixel_sorter::img::{encode_img, load_img, sort_img, SortSettings};
+use serde::{Deserialize, Serialize};
+
+const USAGE: &str = "\
+Sort every frame of an image sequence, writing frames with the same names to the output directory.
+
+Usage: cli <input dir> <output dir> [options]
+
+Options:
+  --settings <file>   JSON sort settings used for every frame
+  --keyframes <file>  JSON list of keyframes ({\"frame\": 1, \"settings\": {...}}) to vary the
+                      settings over the sequence, frames are numbered from 1 in name order
+  --vary-seed         Give every frame its own seed (the settings' seed plus the frame number),
+                      so random effects change from frame to frame instead of holding still
+  --threads <n>       Number of frames to process at once (defaults to the number of CPUs)
+
+Frames in a format that can't be written, like HDR, are saved as EXR if they're floating point
+and PNG otherwise.
+
+Progress is saved to the output directory, so an interrupted run picks up where it left off when
+run again with the same settings.";
+
+const PROGRESS_FILE: &str = ".pixel-sorter-progress";
+
+/// Everything that affects the output, so progress is only resumed for identical runs
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Recipe {
+    settings: SortSettings,
+    keyframes: Vec<Keyframe>,
+    #[serde(default)]
+    vary_seed: bool,
+}
+
+struct Args {
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    recipe: Recipe,
+    threads: usize,
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        exit(1);
+    });
+    if let Err(err) = run(args) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut positional = vec![];
+    let mut settings = SortSettings::default();
+    let mut keyframes = vec![];
+    let mut vary_seed = false;
+    let mut threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--settings" => settings = read_json(&value()?)?,
+            "--keyframes" => keyframes = read_json(&value()?)?,
+            "--vary-seed" => vary_seed = true,
+            "--threads" => {
+                threads = value()?
+                    .parse()
+                    .map_err(|_| "--threads must be a number".to_string())?
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ => positional.push(PathBuf::from(arg)),
+        }
+    }
+
+    let keyframe_settings = keyframes.iter().map(|k: &Keyframe| &k.settings);
+    if !std::iter::once(&settings)
+        .chain(keyframe_settings)
+        .all(SortSettings::is_valid)
+    {
+        return Err("Settings are out of range".to_string());
+    }
+
+    let [input_dir, output_dir]: [PathBuf; 2] = positional
+        .try_into()
+        .map_err(|_| "Expected an input and output directory".to_string())?;
+    Ok(Args {
+        input_dir,
+        output_dir,
+        recipe: Recipe {
+            settings,
+            keyframes,
+            vary_seed,
+        },
+        threads: threads.max(1),
+    })
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &str) -> Result<T, String> {
+    let file = File::open(path).map_err(|err| format!("Couldn't open {}: {}", path, err))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| format!("Couldn't parse {}: {}", path, err))
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let mut frames = fs::read_dir(&args.input_dir)
+        .map_err(|err| format!("Couldn't read {}: {}", args.input_dir.display(), err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok())
+        .collect::<Vec<_>>();
+    frames.sort();
+    if frames.is_empty() {
+        return Err(format!("No images found in {}", args.input_dir.display()));
+    }
+
+    fs::create_dir_all(&args.output_dir)
+        .map_err(|err| format!("Couldn't create {}: {}", args.output_dir.display(), err))?;
+    let done = load_progress(&args.output_dir, &args.recipe);
+    let progress = Mutex::new(open_progress(
+        &args.output_dir,
+        &args.recipe,
+        done.is_empty(),
+    )?);
+
+    let mut animation = AnimationSettings::default();
+    for keyframe in &args.recipe.keyframes {
+        animation.set_keyframe(keyframe.clone());
+    }
+
+    let remaining = frames
+        .iter()
+        .enumerate()
+        .filter(|(_, path)| !done.contains(&file_name(path)))
+        .collect::<Vec<_>>();
+    println!(
+        "Sorting {} of {} frames ({} already done)",
+        remaining.len(),
+        frames.len(),
+        frames.len() - remaining.len()
+    );
+
+    let next = AtomicUsize::new(0);
+    let completed = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..args.threads {
+            scope.spawn(|| {
+                while let Some((i, path)) = remaining.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let mut settings = animation
+                        .settings_at(*i as u32 + 1)
+                        .unwrap_or_else(|| args.recipe.settings.clone());
+                    if args.recipe.vary_seed {
+                        settings.seed = settings.seed.wrapping_add(*i as u64 + 1);
+                    }
+                    let name = file_name(path);
+                    match sort_frame(path, &args.output_dir.join(&name), settings) {
+                        Ok(()) => {
+                            let mut progress = progress.lock().unwrap();
+                            writeln!(progress, "{}", name).unwrap();
+                            let count = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                            println!("[{}/{}] {}", count, remaining.len(), name);
+                        }
+                        Err(err) => {
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            eprintln!("Failed to sort {}: {}", name, err);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    match failed.into_inner() {
+        0 => Ok(()),
+        n => Err(format!("{} frames failed, run again to retry them", n)),
+    }
+}
+
+fn sort_frame(input: &Path, output: &Path, settings: SortSettings) -> Result<(), String> {
+    let data = fs::read(input).map_err(|err| err.to_string())?;
+    let img = load_img(&data).map_err(|err| err.to_string())?;
+    let format = ImageFormat::from_path(output).map_err(|err| err.to_string())?;
+    let sorted = sort_img(img, settings);
+    let (output, data) = match encode_img(&sorted, format) {
+        // Formats that can be read but not written are saved as the closest one that can be
+        Err(ImageError::Unsupported(_)) => {
+            let fallback = match sorted {
+                DynamicImage::ImageRgba32F(_) => ImageFormat::OpenExr,
+                _ => ImageFormat::Png,
+            };
+            let output = output.with_extension(fallback.extensions_str()[0]);
+            (output, encode_img(&sorted, fallback))
+        }
+        data => (output.to_owned(), data),
+    };
+    fs::write(output, data.map_err(|err| err.to_string())?).map_err(|err| err.to_string())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+/// The progress file starts with the recipe as JSON, followed by the name of each finished frame
+fn load_progress(output_dir: &Path, recipe: &Recipe) -> Vec<String> {
+    let Ok(file) = File::open(output_dir.join(PROGRESS_FILE)) else {
+        return vec![];
+    };
+    let mut lines = BufReader::new(file).lines().map_while(Result::ok);
+    match lines
+        .next()
+        .map(|line| serde_json::from_str::<Recipe>(&line))
+    {
+        Some(Ok(saved)) if saved == *recipe => lines.collect(),
+        _ => vec![],
+    }
+}
+
+fn open_progress(output_dir: &Path, recipe: &Recipe, fresh: bool) -> Result<File, String> {
+    let path = output_dir.join(PROGRESS_FILE);
+    let error = |err| format!("Couldn't write {}: {}", path.display(), err);
+    if fresh {
+        let mut file = File::create(&path).map_err(error)?;
+        writeln!(file, "{}", serde_json::to_string(recipe).unwrap()).map_err(error)?;
+        Ok(file)
+    } else {
+        OpenOptions::new().append(true).open(&path).map_err(error)
+    }
+}
@@ -0,0 +1,71 @@
+use web_sys::Element;
+use yew::prelude::*;
+
+use super::pixel_canvas::ImageSource;
+
+/// How the sorted image is shown against the original
+#[derive(PartialEq, Clone, Copy)]
+pub enum View {
+    Sorted,
+    Original,
+    /// Original on the left of a draggable divider, sorted on the right
+    Split,
+    SideBySide,
+}
+
+#[derive(Properties, PartialEq)]
+pub struct Props {
+    pub name: String,
+    pub original: String,
+    pub sorted: ImageSource,
+    #[prop_or(false)]
+    pub side_by_side: bool,
+    #[prop_or_default]
+    pub ondblclick: Callback<MouseEvent>,
+}
+
+/// Before and after comparison of two image sources
+#[function_component(Comparison)]
+pub fn comparison(props: &Props) -> Html {
+    // Percentage across the image
+    let split = use_state(|| 50.0);
+
+    if props.side_by_side {
+        return html! {
+            <div class="comparison side-by-side" ondblclick={props.ondblclick.clone()}>
+                <img src={props.original.clone()} alt={format!("{} (original)", props.name)} />
+                { props.sorted.view(props.name.clone(), Callback::default()) }
+            </div>
+        };
+    }
+
+    // The images and divider ignore the pointer, so offsets are always within the whole comparison
+    let move_divider = |always: bool| {
+        let split = split.clone();
+        Callback::from(move |e: PointerEvent| {
+            if always || e.buttons() == 1 {
+                let target: Element = e.target_unchecked_into();
+                let position = e.offset_x() as f64 / target.client_width().max(1) as f64;
+                split.set((position * 100.0).clamp(0.0, 100.0));
+            }
+        })
+    };
+
+    html! {
+        <div
+            class="comparison split"
+            onpointerdown={move_divider(true)}
+            onpointermove={move_divider(false)}
+            ondblclick={props.ondblclick.clone()}
+        >
+            { props.sorted.view(props.name.clone(), Callback::default()) }
+            <img
+                class="original"
+                style={format!("clip-path: inset(0 {}% 0 0);", 100.0 - *split)}
+                src={props.original.clone()}
+                alt={format!("{} (original)", props.name)}
+            />
+            <div class="divider" style={format!("left: {}%;", *split)} />
+        </div>
+    }
+}
@@ -1,25 +1,359 @@
-use base64::engine::{general_purpose::STANDARD as b64, Engine};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use gloo::events::{EventListener, EventListenerOptions};
+use wasm_bindgen::JsCast;
+use web_sys::{Element, KeyboardEvent, WheelEvent};
 use yew::prelude::*;
 
+use super::comparison::{Comparison, View};
+use super::pixel_canvas::ImageSource;
+
+/// Zooming in stops when each image pixel covers this many screen pixels
+const MAX_PIXEL_SIZE: f64 = 32.0;
+/// Zoom per key press or wheel notch
+const ZOOM_STEP: f64 = 1.25;
+/// Pixels moved per arrow key press
+const PAN_STEP: f64 = 50.0;
+
 #[derive(Properties, PartialEq)]
 pub struct Props {
     pub name: String,
-    #[prop_or("image/jpeg".to_string())]
-    pub file_type: String,
-    pub data: Vec<u8>,
+    pub src: ImageSource,
+    /// Compared against `src` in the split and side by side views
+    #[prop_or_default]
+    pub original: Option<String>,
+    #[prop_or(View::Sorted)]
+    pub view: View,
+    /// Size of the image, needed to show it at 1:1
+    #[prop_or_default]
+    pub dimensions: Option<(u32, u32)>,
     pub onclose: Callback<()>,
 }
 
+/// Zoom relative to the image fitted to the screen, and offset of its centre in pixels
+#[derive(PartialEq, Clone, Copy)]
+struct Transform {
+    scale: f64,
+    x: f64,
+    y: f64,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            scale: 1.0,
+            x: 0.0,
+            y: 0.0,
+        }
+    }
+}
+
+enum TransformAction {
+    /// Zoom by `factor`, keeping the point at `x`, `y` (from the centre of the screen) still
+    ZoomAt {
+        factor: f64,
+        x: f64,
+        y: f64,
+        max: f64,
+    },
+    Pan(f64, f64),
+    SetScale(f64),
+    Fit,
+}
+
+impl Reducible for Transform {
+    type Action = TransformAction;
+
+    fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
+        let transform = match action {
+            TransformAction::ZoomAt { factor, x, y, max } => {
+                let scale = (self.scale * factor).clamp(1.0, max.max(1.0));
+                if scale == 1.0 {
+                    Transform::default()
+                } else {
+                    let factor = scale / self.scale;
+                    Transform {
+                        scale,
+                        x: x - (x - self.x) * factor,
+                        y: y - (y - self.y) * factor,
+                    }
+                }
+            }
+            TransformAction::Pan(dx, dy) => Transform {
+                x: self.x + dx,
+                y: self.y + dy,
+                ..*self
+            },
+            TransformAction::SetScale(scale) => Transform {
+                scale,
+                ..Transform::default()
+            },
+            TransformAction::Fit => Transform::default(),
+        };
+        transform.into()
+    }
+}
+
+/// Scale at which one image pixel covers one screen pixel, if the image size is known
+fn actual_size(
+    content: &NodeRef,
+    dimensions: Option<(u32, u32)>,
+    side_by_side: bool,
+) -> Option<f64> {
+    let (w, h) = dimensions?;
+    let content = content.cast::<Element>()?;
+    let columns = if side_by_side { 2.0 } else { 1.0 };
+    let fitted = (content.client_width() as f64 / columns / w as f64)
+        .min(content.client_height() as f64 / h as f64);
+    (fitted > 0.0).then(|| 1.0 / fitted)
+}
+
+/// Pointer position from the centre of the screen
+fn from_centre(x: i32, y: i32) -> (f64, f64) {
+    let window = gloo::utils::window();
+    let size =
+        |size: Result<wasm_bindgen::JsValue, _>| size.ok().and_then(|s| s.as_f64()).unwrap_or(0.0);
+    (
+        x as f64 - size(window.inner_width()) / 2.0,
+        y as f64 - size(window.inner_height()) / 2.0,
+    )
+}
+
 #[function_component(FullscreenImage)]
 pub fn fullscreen_image(props: &Props) -> Html {
-    let onclose = props.onclose.clone();
+    let transform = use_reducer(Transform::default);
+    let overlay = use_node_ref();
+    let content = use_node_ref();
+    // Pointers currently down, for dragging and pinching
+    let pointers = use_mut_ref(HashMap::<i32, (f64, f64)>::new);
+    // Set once a press turns into a drag, so letting go doesn't count as a click
+    let dragged = use_mut_ref(|| false);
+
+    let side_by_side = props.view == View::SideBySide && props.original.is_some();
+    let max_scale = actual_size(&content, props.dimensions, side_by_side)
+        .map_or(MAX_PIXEL_SIZE, |scale| scale * MAX_PIXEL_SIZE);
+
+    {
+        let transform = transform.dispatcher();
+        let onclose = props.onclose.clone();
+        let (content, dimensions) = (content.clone(), props.dimensions);
+        use_effect_with_deps(
+            move |_| {
+                let listener = EventListener::new(&gloo::utils::document(), "keydown", move |e| {
+                    let Some(e) = e.dyn_ref::<KeyboardEvent>() else {
+                        return;
+                    };
+                    let max = actual_size(&content, dimensions, side_by_side)
+                        .map_or(MAX_PIXEL_SIZE, |scale| scale * MAX_PIXEL_SIZE);
+                    let zoom = |factor| TransformAction::ZoomAt {
+                        factor,
+                        x: 0.0,
+                        y: 0.0,
+                        max,
+                    };
+                    match e.key().as_str() {
+                        "Escape" => onclose.emit(()),
+                        "+" | "=" => transform.dispatch(zoom(ZOOM_STEP)),
+                        "-" => transform.dispatch(zoom(1.0 / ZOOM_STEP)),
+                        "0" => transform.dispatch(TransformAction::Fit),
+                        "1" => {
+                            if let Some(scale) = actual_size(&content, dimensions, side_by_side) {
+                                transform.dispatch(TransformAction::SetScale(scale));
+                            }
+                        }
+                        "ArrowLeft" => transform.dispatch(TransformAction::Pan(PAN_STEP, 0.0)),
+                        "ArrowRight" => transform.dispatch(TransformAction::Pan(-PAN_STEP, 0.0)),
+                        "ArrowUp" => transform.dispatch(TransformAction::Pan(0.0, PAN_STEP)),
+                        "ArrowDown" => transform.dispatch(TransformAction::Pan(0.0, -PAN_STEP)),
+                        _ => return,
+                    }
+                    e.prevent_default();
+                });
+                move || drop(listener)
+            },
+            (side_by_side, dimensions),
+        );
+    }
+
+    // Wheel listeners are passive unless added by hand, and the page mustn't scroll underneath
+    {
+        let transform = transform.dispatcher();
+        let overlay = overlay.clone();
+        use_effect_with_deps(
+            move |max_scale| {
+                let max = *max_scale;
+                let listener = overlay.cast::<Element>().map(|element| {
+                    EventListener::new_with_options(
+                        &element,
+                        "wheel",
+                        EventListenerOptions::enable_prevent_default(),
+                        move |e| {
+                            let Some(e) = e.dyn_ref::<WheelEvent>() else {
+                                return;
+                            };
+                            e.prevent_default();
+                            let (x, y) = from_centre(e.client_x(), e.client_y());
+                            let factor = if e.delta_y() < 0.0 {
+                                ZOOM_STEP
+                            } else {
+                                1.0 / ZOOM_STEP
+                            };
+                            transform.dispatch(TransformAction::ZoomAt { factor, x, y, max });
+                        },
+                    )
+                });
+                move || drop(listener)
+            },
+            max_scale,
+        );
+    }
+
+    let onpointerdown = {
+        let pointers = pointers.clone();
+        let dragged = dragged.clone();
+        Callback::from(move |e: PointerEvent| {
+            let mut pointers = pointers.borrow_mut();
+            if pointers.is_empty() {
+                *dragged.borrow_mut() = false;
+            }
+            pointers.insert(e.pointer_id(), (e.client_x() as f64, e.client_y() as f64));
+        })
+    };
+    let onpointermove = {
+        let pointers = pointers.clone();
+        let dragged = dragged.clone();
+        let transform = transform.dispatcher();
+        Callback::from(move |e: PointerEvent| {
+            let mut pointers = pointers.borrow_mut();
+            let Some(&(last_x, last_y)) = pointers.get(&e.pointer_id()) else {
+                return;
+            };
+            let (x, y) = (e.client_x() as f64, e.client_y() as f64);
+            match pointers.len() {
+                1 => transform.dispatch(TransformAction::Pan(x - last_x, y - last_y)),
+                // Pinch to zoom, about the point between the two fingers
+                2 => {
+                    let &(other_x, other_y) = pointers
+                        .iter()
+                        .find(|(&id, _)| id != e.pointer_id())
+                        .map(|(_, position)| position)
+                        .unwrap();
+                    let before = (last_x - other_x).hypot(last_y - other_y);
+                    let after = (x - other_x).hypot(y - other_y);
+                    let (centre_x, centre_y) =
+                        from_centre(((x + other_x) / 2.0) as i32, ((y + other_y) / 2.0) as i32);
+                    if before > 0.0 {
+                        transform.dispatch(TransformAction::ZoomAt {
+                            factor: after / before,
+                            x: centre_x,
+                            y: centre_y,
+                            max: max_scale,
+                        });
+                    }
+                    transform
+                        .dispatch(TransformAction::Pan((x - last_x) / 2.0, (y - last_y) / 2.0));
+                }
+                _ => return,
+            }
+            if (x - last_x).abs() + (y - last_y).abs() > 0.0 {
+                *dragged.borrow_mut() = true;
+            }
+            pointers.insert(e.pointer_id(), (x, y));
+        })
+    };
+    let onpointerup = {
+        let pointers = pointers.clone();
+        Callback::from(move |e: PointerEvent| {
+            pointers.borrow_mut().remove(&e.pointer_id());
+        })
+    };
+    let onclick = {
+        let onclose = props.onclose.clone();
+        Callback::from(move |_: MouseEvent| {
+            if !dragged.replace(false) {
+                onclose.emit(());
+            }
+        })
+    };
+
+    let onfit = {
+        let transform = transform.dispatcher();
+        Callback::from(move |e: MouseEvent| {
+            e.stop_propagation();
+            transform.dispatch(TransformAction::Fit);
+        })
+    };
+    let onactual = {
+        let transform = transform.dispatcher();
+        let content = content.clone();
+        let dimensions = props.dimensions;
+        Callback::from(move |e: MouseEvent| {
+            e.stop_propagation();
+            if let Some(scale) = actual_size(&content, dimensions, side_by_side) {
+                transform.dispatch(TransformAction::SetScale(scale));
+            }
+        })
+    };
+    let onclosebutton = {
+        let onclose = props.onclose.clone();
+        Callback::from(move |e: MouseEvent| {
+            e.stop_propagation();
+            onclose.emit(());
+        })
+    };
+
+    let image = match (&props.original, props.view) {
+        (Some(original), view @ (View::Split | View::SideBySide)) => html! {
+            // Dragging the divider shouldn't pan or close the viewer
+            <div
+                class="comparison-container"
+                onclick={Callback::from(|e: MouseEvent| e.stop_propagation())}
+                onpointerdown={Callback::from(|e: PointerEvent| e.stop_propagation())}
+            >
+                <Comparison
+                    name={props.name.clone()}
+                    original={original.clone()}
+                    sorted={props.src.clone()}
+                    side_by_side={view == View::SideBySide}
+                />
+            </div>
+        },
+        _ => props.src.view(props.name.clone(), Callback::default()),
+    };
+    // Shown relative to the image's actual size where possible
+    let actual = actual_size(&content, props.dimensions, side_by_side);
+    let pixelated = actual.is_some_and(|scale| transform.scale >= scale);
+    let zoom = transform.scale / actual.unwrap_or(1.0);
 
     html! {
-        <div class="fullscreen-overlay" onclick={Callback::from(move |_| onclose.emit(()))}>
-            <img
-                src={format!("data:{};base64,{}", props.file_type, b64.encode(props.data.as_slice()))}
-                alt={props.name.clone()}
-            />
+        <div
+            class="fullscreen-overlay"
+            ref={overlay}
+            {onclick}
+            {onpointerdown}
+            {onpointermove}
+            onpointerup={onpointerup.clone()}
+            onpointercancel={onpointerup}
+        >
+            <div
+                class={classes!("fullscreen-content", pixelated.then_some("pixelated"))}
+                ref={content}
+                style={format!(
+                    "transform: translate({}px, {}px) scale({});",
+                    transform.x, transform.y, transform.scale
+                )}
+            >
+                { image }
+            </div>
+            <div class="fullscreen-toolbar" onpointerdown={Callback::from(|e: PointerEvent| e.stop_propagation())}>
+                <button class="btn" title="Fit to screen (0)" onclick={onfit}>{ "Fit" }</button>
+                if props.dimensions.is_some() {
+                    <button class="btn" title="Actual size (1)" onclick={onactual}>{ "1:1" }</button>
+                }
+                <span>{ format!("{:.0}%", zoom * 100.0) }</span>
+                <button class="btn" title="Close (Esc)" onclick={onclosebutton}>{ "Close" }</button>
+            </div>
         </div>
     }
 }
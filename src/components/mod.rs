@@ -1,5 +1,9 @@
+mod comparison;
 mod fullscreen_image;
 mod header;
+mod pixel_canvas;
 
+pub use comparison::{Comparison, View};
 pub use fullscreen_image::FullscreenImage;
 pub use header::Header;
+pub use pixel_canvas::ImageSource;
@@ -0,0 +1,72 @@
+use std::rc::Rc;
+
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+use yew::prelude::*;
+
+use crate::agent::RawImage;
+
+/// Something that can be shown as an image: a file behind a URL, or pixels drawn to a canvas
+#[derive(Clone, PartialEq)]
+pub enum ImageSource {
+    Url(String),
+    Pixels(Rc<RawImage>),
+}
+
+impl ImageSource {
+    pub fn view(&self, alt: String, onclick: Callback<MouseEvent>) -> Html {
+        match self {
+            ImageSource::Url(src) => html! {
+                <img src={src.clone()} {alt} {onclick} />
+            },
+            ImageSource::Pixels(image) => html! {
+                <PixelCanvas image={image.clone()} name={alt} {onclick} />
+            },
+        }
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct Props {
+    pub image: Rc<RawImage>,
+    pub name: String,
+    #[prop_or_default]
+    pub onclick: Callback<MouseEvent>,
+}
+
+#[function_component(PixelCanvas)]
+pub fn pixel_canvas(props: &Props) -> Html {
+    let canvas = use_node_ref();
+
+    {
+        let canvas = canvas.clone();
+        use_effect_with_deps(
+            move |image: &Rc<RawImage>| {
+                let context = canvas
+                    .cast::<HtmlCanvasElement>()
+                    .and_then(|canvas| canvas.get_context("2d").ok().flatten())
+                    .map(|context| context.unchecked_into::<CanvasRenderingContext2d>());
+                let data = ImageData::new_with_u8_clamped_array_and_sh(
+                    Clamped(&image.data),
+                    image.width,
+                    image.height,
+                );
+                if let (Some(context), Ok(data)) = (context, data) {
+                    context.put_image_data(&data, 0.0, 0.0).ok();
+                }
+            },
+            props.image.clone(),
+        );
+    }
+
+    html! {
+        <canvas
+            ref={canvas}
+            width={props.image.width.to_string()}
+            height={props.image.height.to_string()}
+            role="img"
+            aria-label={props.name.clone()}
+            onclick={props.onclick.clone()}
+        />
+    }
+}
@@ -1,13 +1,89 @@
+use std::f32::consts::{FRAC_PI_4, TAU};
 use std::io::{BufReader, BufWriter, Cursor, Write};
+use std::ops::Range;
 
-use image::{DynamicImage, GenericImageView, ImageBuffer, ImageFormat, Pixel, Rgba};
+use image::{
+    ColorType, DynamicImage, ImageBuffer, ImageFormat, ImageResult, Pixel, Primitive, Rgba,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::rng::Rng;
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
 pub enum Direction {
     #[default]
     Horizontal,
     Vertical,
+    /// Along the outlines in the image, at right angles to its gradient
+    Contour,
+    /// Rows bent into the shape set in `SortSettings::wave`
+    Wave,
+    /// One path spiralling clockwise from the edges into the middle
+    Spiral,
+    /// One path along a Hilbert curve
+    Hilbert,
+    /// One path in Z-order (Morton order)
+    ZOrder,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum WaveShape {
+    #[default]
+    Sine,
+    ZigZag,
+    /// Arches of a cubic Bezier curve
+    Bezier,
+}
+
+/// Largest wave amplitude and wavelength accepted, in pixels
+pub const MAX_WAVE_AMPLITUDE: u32 = 10_000;
+pub const MAX_WAVELENGTH: u32 = 100_000;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Wave {
+    pub shape: WaveShape,
+    /// Pixels up and down from the middle of the row
+    pub amplitude: u32,
+    pub wavelength: u32,
+    /// Degrees through the first wave at the left edge
+    pub phase: u16,
+}
+
+impl Default for Wave {
+    fn default() -> Self {
+        Wave {
+            shape: WaveShape::Sine,
+            amplitude: 20,
+            wavelength: 200,
+            phase: 0,
+        }
+    }
+}
+
+impl Wave {
+    /// How far the row is shifted down at column `x`
+    fn offset(&self, x: u32) -> i64 {
+        let t =
+            (x as f32 / self.wavelength.max(1) as f32 + self.phase as f32 / 360.0).rem_euclid(1.0);
+        let height = match self.shape {
+            WaveShape::Sine => (t * TAU).sin(),
+            WaveShape::ZigZag => match t {
+                t if t < 0.25 => 4.0 * t,
+                t if t < 0.75 => 2.0 - 4.0 * t,
+                t => 4.0 * t - 4.0,
+            },
+            WaveShape::Bezier => {
+                // Control points at heights 0, 1, 1, 0 peak at 3/4
+                let arch = |s: f32| 3.0 * s * (1.0 - s) * 4.0 / 3.0;
+                if t < 0.5 {
+                    arch(t * 2.0)
+                } else {
+                    -arch(t * 2.0 - 1.0)
+                }
+            }
+        };
+        (height * self.amplitude as f32).round() as i64
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
@@ -17,99 +93,1099 @@ pub enum Order {
     Descending,
 }
 
+/// Colour space that sort keys and thresholds are measured in
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum ColourSpace {
+    /// Luma for lightness, and HSV chroma and hue
+    #[default]
+    Rgb,
+    /// OKLab lightness, and OKLCh chroma and hue
+    OkLab,
+    /// CIELAB lightness, and CIELCh chroma and hue
+    CieLab,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub enum SortKey {
+    /// Sum of the channels, whatever the colour space
+    #[default]
+    Brightness,
+    Lightness,
+    Chroma,
+    Hue,
+}
+
+/// Quantity the image mask's thresholds are compared against, scaled to 0-255
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum MaskSource {
+    #[default]
+    Lightness,
+    /// Wraps around, so a lower threshold above the upper threshold selects across red
+    Hue,
+    Saturation,
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    /// Distance to a colour, where black and white are 255 apart
+    Distance([u8; 3]),
+}
+
+/// Pixels with a mask value between the thresholds are in the band
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
-pub struct SortSettings {
+pub struct MaskBand {
+    pub source: MaskSource,
     pub lower_threshold: u8,
     pub upper_threshold: u8,
+}
+
+impl Default for MaskBand {
+    fn default() -> Self {
+        MaskBand {
+            source: MaskSource::Lightness,
+            lower_threshold: 75,
+            upper_threshold: 175,
+        }
+    }
+}
+
+impl MaskBand {
+    pub fn wraps(&self) -> bool {
+        self.source == MaskSource::Hue && self.lower_threshold > self.upper_threshold
+    }
+
+    pub fn contains(&self, value: f32) -> bool {
+        let (lower, upper) = (self.lower_threshold as f32, self.upper_threshold as f32);
+        if self.wraps() {
+            value >= lower || value <= upper
+        } else {
+            value >= lower && value <= upper
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum MaskCombine {
+    /// Pixels in any band are sorted
+    #[default]
+    Union,
+    /// Only pixels in every band are sorted
+    Intersection,
+}
+
+/// Determines which pixels are sorted
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Mask {
+    /// Never empty
+    pub bands: Vec<MaskBand>,
+    pub combine: MaskCombine,
+    /// Sort the pixels outside the bands instead
+    pub invert: bool,
+}
+
+impl Default for Mask {
+    fn default() -> Self {
+        Mask {
+            bands: vec![MaskBand::default()],
+            combine: MaskCombine::Union,
+            invert: false,
+        }
+    }
+}
+
+impl Mask {
+    /// Whether a pixel is selected, given a test of whether it's in the band at an index
+    pub fn selects(&self, mut in_band: impl FnMut(usize, &MaskBand) -> bool) -> bool {
+        let mut bands = self.bands.iter().enumerate();
+        let selected = match self.combine {
+            MaskCombine::Union => bands.any(|(i, band)| in_band(i, band)),
+            MaskCombine::Intersection => bands.all(|(i, band)| in_band(i, band)),
+        };
+        selected != self.invert
+    }
+
+    pub fn lerp(&self, other: &Mask, t: f32) -> Mask {
+        let nearest = if t < 0.5 { self } else { other };
+        if self.bands.len() != other.bands.len() {
+            return nearest.clone();
+        }
+        let lerp_u8 = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        Mask {
+            bands: self
+                .bands
+                .iter()
+                .zip(&other.bands)
+                .zip(&nearest.bands)
+                .map(|((a, b), nearest)| MaskBand {
+                    source: nearest.source.clone(),
+                    lower_threshold: lerp_u8(a.lower_threshold, b.lower_threshold),
+                    upper_threshold: lerp_u8(a.upper_threshold, b.upper_threshold),
+                })
+                .collect(),
+            combine: nearest.combine.clone(),
+            invert: nearest.invert,
+        }
+    }
+}
+
+/// How transparency affects sorting
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum AlphaMode {
+    /// Alpha moves with the pixel and counts towards its brightness
+    #[default]
+    Included,
+    /// Fully transparent pixels break spans and are never moved
+    SkipTransparent,
+    /// Brightness is taken from the colour premultiplied by alpha
+    Premultiplied,
+    /// Alpha stays where it is and only the colour is sorted
+    FixedAlpha,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub enum OutputFormat {
+    /// Used for opaque 8-bit still images (png encoding is really slow)
+    #[default]
+    Jpeg,
+    Gif,
+    /// Animated png, or a still image with more than 8 bits per channel
+    Png,
+    /// Png image sequence
+    Zip,
+}
+
+impl OutputFormat {
+    /// Format for a still image sorted from `source`, which only uses png when it would otherwise
+    /// lose precision or transparency
+    pub fn for_image(source: &DynamicImage) -> OutputFormat {
+        match source.color() {
+            ColorType::L8 | ColorType::Rgb8 => OutputFormat::Jpeg,
+            _ => OutputFormat::Png,
+        }
+    }
+
+    pub fn image_format(&self) -> Option<ImageFormat> {
+        match self {
+            OutputFormat::Jpeg => Some(ImageFormat::Jpeg),
+            OutputFormat::Gif => Some(ImageFormat::Gif),
+            OutputFormat::Png => Some(ImageFormat::Png),
+            OutputFormat::Zip => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Gif => "image/gif",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Zip => "application/zip",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Png => "png",
+            OutputFormat::Zip => "zip",
+        }
+    }
+
+    /// Whether the browser can display this format as an image
+    pub fn is_image(&self) -> bool {
+        *self != OutputFormat::Zip
+    }
+}
+
+/// Rectangle of the image in pixels, after EXIF rotation
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// The part of the region inside an image of size `w` by `h`, if any
+    pub fn clamped(&self, (w, h): (u32, u32)) -> Option<Region> {
+        let region = Region {
+            x: self.x,
+            y: self.y,
+            width: self.width.min(w.saturating_sub(self.x)),
+            height: self.height.min(h.saturating_sub(self.y)),
+        };
+        (region.width > 0 && region.height > 0).then_some(region)
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x.checked_sub(self.x).is_some_and(|dx| dx < self.width)
+            && y.checked_sub(self.y).is_some_and(|dy| dy < self.height)
+    }
+}
+
+/// How the sorted image is combined with the original, with the sorted image on top
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Difference,
+    Lighten,
+    Darken,
+}
+
+impl BlendMode {
+    /// Blend channels from 0-1
+    fn blend(&self, original: f32, sorted: f32) -> f32 {
+        match self {
+            BlendMode::Normal => sorted,
+            BlendMode::Multiply => original * sorted,
+            BlendMode::Screen => 1.0 - (1.0 - original) * (1.0 - sorted),
+            BlendMode::Overlay if original < 0.5 => 2.0 * original * sorted,
+            BlendMode::Overlay => 1.0 - 2.0 * (1.0 - original) * (1.0 - sorted),
+            BlendMode::Difference => (original - sorted).abs(),
+            BlendMode::Lighten => original.max(sorted),
+            BlendMode::Darken => original.min(sorted),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Blend {
+    pub mode: BlendMode,
+    /// Percentage of the blended result over the original
+    pub opacity: u8,
+}
+
+impl Default for Blend {
+    fn default() -> Self {
+        Blend {
+            mode: BlendMode::Normal,
+            opacity: 100,
+        }
+    }
+}
+
+impl Blend {
+    /// Mix `sorted` into `original`, with `weight` from 0-1 scaling the opacity
+    fn apply<S: Subpixel>(&self, original: &Rgba<S>, sorted: &Rgba<S>, weight: f32) -> Rgba<S>
+    where
+        Rgba<S>: Pixel<Subpixel = S>,
+    {
+        let max = S::DEFAULT_MAX_VALUE.into();
+        let t = self.opacity as f32 / 100.0 * weight;
+        let mut pixel = *original;
+        for c in 0..4 {
+            let (a, b) = (original.0[c].into() / max, sorted.0[c].into() / max);
+            // Alpha isn't blended, just faded between the two
+            let blended = if c == 3 { b } else { self.mode.blend(a, b) };
+            pixel.0[c] = S::from_unit(a + (blended - a) * t);
+        }
+        pixel
+    }
+}
+
+/// What to render instead of the sorted image, to help tune the settings
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum Preview {
+    #[default]
+    Sorted,
+    /// White where pixels get sorted
+    Mask,
+    /// White on the detected edges
+    Edges,
+}
+
+/// Where spans longer than the maximum length are cut
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub enum SpanSplit {
+    /// Every `max_span_length` pixels
+    #[default]
+    Fixed,
+    /// At random lengths between the minimum and maximum span length
+    Random,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct SortSettings {
+    pub mask: Mask,
     pub direction: Direction,
     pub order: Order,
+    #[serde(default)]
+    pub alpha_mode: AlphaMode,
+    #[serde(default)]
+    pub wave: Wave,
+    #[serde(default)]
+    pub sort_key: SortKey,
+    /// Breaks ties between pixels with the same sort key
+    #[serde(default)]
+    pub secondary_key: Option<SortKey>,
+    /// Keep pixels that still tie in their original order, rather than whatever order is fastest
+    #[serde(default)]
+    pub stable_sort: bool,
+    #[serde(default)]
+    pub colour_space: ColourSpace,
+    /// Applied after sorting to tone the effect down
+    #[serde(default)]
+    pub blend: Blend,
+    /// Pixels over which the sorted image fades out towards the edges of the mask, 0 for hard edges
+    #[serde(default)]
+    pub feather: u32,
+    /// Only pixels inside these are sorted, or the whole image if there are none
+    #[serde(default)]
+    pub regions: Vec<Region>,
+    /// How readily outlines are detected and used as span boundaries, 0 to turn edge detection off
+    #[serde(default)]
+    pub edge_sensitivity: u8,
+    /// Spans shorter than this are left unsorted, 0 for no minimum
+    #[serde(default)]
+    pub min_span_length: u32,
+    /// Spans longer than this are split up, 0 for no maximum
+    #[serde(default)]
+    pub max_span_length: u32,
+    #[serde(default)]
+    pub span_split: SpanSplit,
+    /// Drives all randomness, so the same settings always give the same image
+    #[serde(default)]
+    pub seed: u64,
 }
 
 impl Default for SortSettings {
     fn default() -> Self {
         SortSettings {
-            lower_threshold: 75,
-            upper_threshold: 175,
+            mask: Mask::default(),
             direction: Direction::Horizontal,
             order: Order::Ascending,
+            alpha_mode: AlphaMode::Included,
+            wave: Wave::default(),
+            sort_key: SortKey::Brightness,
+            secondary_key: None,
+            stable_sort: false,
+            colour_space: ColourSpace::Rgb,
+            blend: Blend::default(),
+            feather: 0,
+            regions: vec![],
+            edge_sensitivity: 0,
+            min_span_length: 0,
+            max_span_length: 0,
+            span_split: SpanSplit::Fixed,
+            seed: 0,
+        }
+    }
+}
+
+impl SortSettings {
+    /// Whether settings from outside the app, such as a shared link, are safe to sort with
+    pub fn is_valid(&self) -> bool {
+        !self.mask.bands.is_empty()
+            && self.regions.iter().all(|region| {
+                region.x.checked_add(region.width).is_some()
+                    && region.y.checked_add(region.height).is_some()
+            })
+            && self.wave.amplitude <= MAX_WAVE_AMPLITUDE
+            && (1..=MAX_WAVELENGTH).contains(&self.wave.wavelength)
+            && self.blend.opacity <= 100
+    }
+
+    /// Blend between two settings, where `t` goes from 0 (all `self`) to 1 (all `other`).
+    /// Thresholds are interpolated when both masks have the same number of bands, everything else
+    /// switches over halfway.
+    pub fn lerp(&self, other: &SortSettings, t: f32) -> SortSettings {
+        let nearest = if t < 0.5 { self } else { other };
+        let lerp_u32 = |a: u32, b: u32| (a as f32 + (b as f32 - a as f32) * t).round() as u32;
+        SortSettings {
+            mask: self.mask.lerp(&other.mask, t),
+            direction: nearest.direction.clone(),
+            order: nearest.order.clone(),
+            alpha_mode: nearest.alpha_mode.clone(),
+            wave: Wave {
+                shape: nearest.wave.shape.clone(),
+                amplitude: lerp_u32(self.wave.amplitude, other.wave.amplitude),
+                wavelength: lerp_u32(self.wave.wavelength, other.wave.wavelength),
+                phase: lerp_u32(self.wave.phase as u32, other.wave.phase as u32) as u16,
+            },
+            sort_key: nearest.sort_key,
+            secondary_key: nearest.secondary_key,
+            stable_sort: nearest.stable_sort,
+            colour_space: nearest.colour_space.clone(),
+            blend: Blend {
+                mode: nearest.blend.mode.clone(),
+                opacity: lerp_u32(self.blend.opacity as u32, other.blend.opacity as u32) as u8,
+            },
+            feather: lerp_u32(self.feather, other.feather),
+            regions: nearest.regions.clone(),
+            edge_sensitivity: lerp_u32(self.edge_sensitivity as u32, other.edge_sensitivity as u32)
+                as u8,
+            min_span_length: lerp_u32(self.min_span_length, other.min_span_length),
+            max_span_length: lerp_u32(self.max_span_length, other.max_span_length),
+            span_split: nearest.span_split.clone(),
+            seed: nearest.seed,
+        }
+    }
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_srgb_to_oklab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let l = (0.4122215 * r + 0.5363325 * g + 0.051446 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.107397 * b).cbrt();
+    let s = (0.0883025 * r + 0.2817188 * g + 0.6299787 * b).cbrt();
+    [
+        0.2104543 * l + 0.7936178 * m - 0.004072 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.025904 * l + 0.7827718 * m - 0.8086758 * s,
+    ]
+}
+
+/// CIELAB relative to the D65 white point
+pub fn linear_srgb_to_cielab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let x = f((0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047);
+    let y = f(0.2126729 * r + 0.7151522 * g + 0.0721750 * b);
+    let z = f((0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883);
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+/// Polar form of a Lab colour: lightness, chroma and hue in degrees
+pub fn lab_to_lch([l, a, b]: [f32; 3]) -> [f32; 3] {
+    [l, a.hypot(b), b.atan2(a).to_degrees().rem_euclid(360.0)]
+}
+
+/// HSV style chroma and hue, with luma for lightness
+fn srgb_to_lch([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    let hue = if chroma == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / chroma)
+    } else if max == g {
+        60.0 * ((b - r) / chroma + 2.0)
+    } else {
+        60.0 * ((r - g) / chroma + 4.0)
+    };
+    [
+        0.2126 * r + 0.7152 * g + 0.0722 * b,
+        chroma,
+        hue.rem_euclid(360.0),
+    ]
+}
+
+/// Lightness (0-1), chroma and hue (in degrees) of an sRGB colour with channels from 0-1
+pub fn srgb_to_lch_in(rgb: [f32; 3], space: &ColourSpace) -> [f32; 3] {
+    match space {
+        ColourSpace::Rgb => srgb_to_lch(rgb),
+        ColourSpace::OkLab => lab_to_lch(linear_srgb_to_oklab(rgb.map(srgb_to_linear))),
+        ColourSpace::CieLab => {
+            let [l, c, h] = lab_to_lch(linear_srgb_to_cielab(rgb.map(srgb_to_linear)));
+            [l / 100.0, c, h]
+        }
+    }
+}
+
+/// Saturation (0-1) of an sRGB colour with channels from 0-1. In the Lab spaces this is chroma
+/// relative to the most saturated sRGB colour.
+pub fn saturation_in(rgb: [f32; 3], space: &ColourSpace) -> f32 {
+    let [l, c, _] = srgb_to_lch_in(rgb, space);
+    let saturation = match space {
+        ColourSpace::Rgb => {
+            let max = rgb[0].max(rgb[1]).max(rgb[2]);
+            if max == 0.0 {
+                0.0
+            } else {
+                c / max
+            }
+        }
+        ColourSpace::OkLab => c / 0.3225,
+        ColourSpace::CieLab if l > 0.0 => c / 133.8,
+        ColourSpace::CieLab => 0.0,
+    };
+    saturation.min(1.0)
+}
+
+/// Coordinates of an sRGB colour with channels from 0-1 in `space`, scaled so that black and white
+/// are 1 apart
+pub fn coordinates_in(rgb: [f32; 3], space: &ColourSpace) -> [f32; 3] {
+    match space {
+        ColourSpace::Rgb => rgb.map(|c| c / 3f32.sqrt()),
+        ColourSpace::OkLab => linear_srgb_to_oklab(rgb.map(srgb_to_linear)),
+        ColourSpace::CieLab => linear_srgb_to_cielab(rgb.map(srgb_to_linear)).map(|c| c / 100.0),
+    }
+}
+
+/// Subpixel types images can be sorted in, so 16-bit and HDR images keep their precision
+pub trait Subpixel: Primitive + Into<f32> {
+    /// From a value where 0-1 is the full range
+    fn from_unit(v: f32) -> Self;
+}
+
+impl Subpixel for u8 {
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8
+    }
+}
+
+impl Subpixel for u16 {
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+    }
+}
+
+impl Subpixel for f32 {
+    fn from_unit(v: f32) -> Self {
+        v
+    }
+}
+
+/// Sort an image in its native bit depth. The output always has an alpha channel.
+pub fn sort_img(img: DynamicImage, settings: SortSettings) -> DynamicImage {
+    preview_img(img, settings, &Preview::Sorted)
+}
+
+pub fn preview_img(img: DynamicImage, settings: SortSettings, preview: &Preview) -> DynamicImage {
+    match img.color() {
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+            DynamicImage::ImageRgba16(render_buffer(&img.into_rgba16(), settings, preview))
         }
+        ColorType::Rgb32F | ColorType::Rgba32F => {
+            DynamicImage::ImageRgba32F(render_buffer(&img.into_rgba32f(), settings, preview))
+        }
+        _ => DynamicImage::ImageRgba8(render_buffer(&img.into_rgba8(), settings, preview)),
     }
 }
 
-pub fn sort_img(img: DynamicImage, settings: SortSettings) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+pub fn sort_buffer<S: Subpixel>(
+    img: &ImageBuffer<Rgba<S>, Vec<S>>,
+    settings: SortSettings,
+) -> ImageBuffer<Rgba<S>, Vec<S>>
+where
+    Rgba<S>: Pixel<Subpixel = S>,
+{
+    render_buffer(img, settings, &Preview::Sorted)
+}
+
+fn render_buffer<S: Subpixel>(
+    img: &ImageBuffer<Rgba<S>, Vec<S>>,
+    settings: SortSettings,
+    preview: &Preview,
+) -> ImageBuffer<Rgba<S>, Vec<S>>
+where
+    Rgba<S>: Pixel<Subpixel = S>,
+{
     let (w, h) = img.dimensions();
-    let mut output = ImageBuffer::new(w, h);
+    let mut output = img.clone();
+    // Thresholds are 0-255 whatever the bit depth
+    let scale = 255.0 / S::DEFAULT_MAX_VALUE.into();
+    let alpha = |p: &Rgba<S>| p.0[3].into() / S::DEFAULT_MAX_VALUE.into();
+    // Channels from 0-1, premultiplied if need be
+    let colour = |p: &Rgba<S>| {
+        let rgb = [0, 1, 2].map(|c| p.0[c].into() / S::DEFAULT_MAX_VALUE.into());
+        match settings.alpha_mode {
+            AlphaMode::Premultiplied => rgb.map(|c| c * alpha(p)),
+            _ => rgb,
+        }
+    };
+    let is_transparent = |p: &Rgba<S>| {
+        settings.alpha_mode == AlphaMode::SkipTransparent && p.0[3] == S::DEFAULT_MIN_VALUE
+    };
+    let space = &settings.colour_space;
+    // Colours to measure distance from, for each band
+    let targets = settings
+        .mask
+        .bands
+        .iter()
+        .map(|band| match &band.source {
+            MaskSource::Distance(rgb) => coordinates_in(rgb.map(|c| c as f32 / 255.0), space),
+            _ => [0.0; 3],
+        })
+        .collect::<Vec<_>>();
+    let mask_value = |p: &Rgba<S>, source: &MaskSource, target: &[f32; 3]| match source {
+        MaskSource::Lightness => match space {
+            ColourSpace::Rgb => {
+                let mut luminance = p.to_luma().0[0].into() * scale;
+                if settings.alpha_mode == AlphaMode::Premultiplied {
+                    luminance *= alpha(p);
+                }
+                luminance
+            }
+            _ => srgb_to_lch_in(colour(p), space)[0] * 255.0,
+        },
+        MaskSource::Hue => srgb_to_lch_in(colour(p), space)[2] / 360.0 * 255.0,
+        MaskSource::Saturation => saturation_in(colour(p), space) * 255.0,
+        MaskSource::Red => p.0[0].into() * scale,
+        MaskSource::Green => p.0[1].into() * scale,
+        MaskSource::Blue => p.0[2].into() * scale,
+        MaskSource::Alpha => p.0[3].into() * scale,
+        MaskSource::Distance(_) => {
+            let coordinates = coordinates_in(colour(p), space);
+            let distance = (0..3)
+                .map(|i| (coordinates[i] - target[i]).powi(2))
+                .sum::<f32>()
+                .sqrt();
+            (distance * 255.0).min(255.0)
+        }
+    };
+    let gradients = if settings.edge_sensitivity > 0 || settings.direction == Direction::Contour {
+        let lightness = img
+            .pixels()
+            .map(|p| srgb_to_lch_in(colour(p), space)[0])
+            .collect::<Vec<_>>();
+        sobel(&lightness, w, h)
+    } else {
+        vec![]
+    };
+    let edges = (settings.edge_sensitivity > 0)
+        .then(|| edge_map(&gradients, w, h, settings.edge_sensitivity));
+    let is_edge = |x: u32, y: u32| edges.as_ref().is_some_and(|e| e[(y * w + x) as usize]);
+    // Pixels that never move
+    let is_fixed = |x: u32, y: u32, p: &Rgba<S>| {
+        let outside = !settings.regions.is_empty()
+            && !settings.regions.iter().any(|region| region.contains(x, y));
+        outside || is_transparent(p)
+    };
+    let is_selected = |x: u32, y: u32, p: &Rgba<S>| {
+        let selected = settings
+            .mask
+            .selects(|i, band| band.contains(mask_value(p, &band.source, &targets[i])));
+        selected && !is_fixed(x, y, p)
+    };
+    let is_span_break = |x: u32, y: u32, p: &Rgba<S>| !is_selected(x, y, p) || is_edge(x, y);
+    // How much of the sorted image shows through at each pixel, fading in from the mask's edges.
+    // Only needed to blend, and 0 wherever sorting isn't allowed
+    let weights = (settings.blend != Blend::default() || settings.feather > 0).then(|| {
+        let mut weights = img
+            .enumerate_pixels()
+            .map(|(x, y, p)| if is_selected(x, y, p) { 1.0 } else { 0.0 })
+            .collect::<Vec<f32>>();
+        if settings.feather > 0 {
+            // Two box blurs are close enough to a Gaussian
+            for _ in 0..2 {
+                box_blur(&mut weights, w, h, settings.feather.div_ceil(2));
+            }
+            // Fully faded out at the edge itself, rather than halfway
+            weights
+                .iter_mut()
+                .for_each(|v| *v = (*v * 2.0 - 1.0).clamp(0.0, 1.0));
+        }
+        weights
+    });
 
-    match settings.direction {
-        Direction::Horizontal => {
-            for y in 0..h {
-                let mut span_start: u32 = 0;
-                for x in 0..w {
-                    let pixel = img.get_pixel(x, y);
-                    let luminance = pixel.to_luma().0[0];
-                    output.put_pixel(x, y, pixel);
-                    if luminance < settings.lower_threshold
-                        || luminance > settings.upper_threshold
-                        || x == w - 1
-                    {
-                        if (span_start as isize) < (x as isize) - 1 {
-                            // Sort the span
-                            let mut span = (span_start..x)
-                                .map(|x| img.get_pixel(x, y))
-                                .collect::<Vec<_>>();
-                            span.sort_unstable_by_key(|p| {
-                                p.0[0] as u32 + p.0[1] as u32 + p.0[2] as u32 + p.0[3] as u32
-                            });
-                            if settings.order == Order::Descending {
-                                span.reverse();
-                            }
-                            for i in span_start..x {
-                                if let Some(pixel) = span.get((i - span_start) as usize) {
-                                    output.put_pixel(i, y, *pixel);
-                                }
-                            }
+    if *preview != Preview::Sorted {
+        return ImageBuffer::from_fn(w, h, |x, y| {
+            let value = match (preview, &weights) {
+                (Preview::Edges, _) => is_edge(x, y) as u8 as f32,
+                (_, Some(weights)) if settings.feather > 0 => weights[(y * w + x) as usize],
+                _ => !is_span_break(x, y, img.get_pixel(x, y)) as u8 as f32,
+            };
+            let c = S::from_unit(value);
+            Rgba([c, c, c, S::DEFAULT_MAX_VALUE])
+        });
+    }
+
+    let sort_key = |p: &Rgba<S>, key: SortKey| match key {
+        SortKey::Brightness => {
+            let rgb = p.0[..3].iter().map(|&c| c.into()).sum::<f32>();
+            match settings.alpha_mode {
+                AlphaMode::Included => rgb + p.0[3].into(),
+                AlphaMode::Premultiplied => rgb * alpha(p),
+                AlphaMode::SkipTransparent | AlphaMode::FixedAlpha => rgb,
+            }
+        }
+        SortKey::Lightness => srgb_to_lch_in(colour(p), &settings.colour_space)[0],
+        SortKey::Chroma => srgb_to_lch_in(colour(p), &settings.colour_space)[1],
+        SortKey::Hue => srgb_to_lch_in(colour(p), &settings.colour_space)[2],
+    };
+    // Only the colour is moved when alpha is fixed in place
+    let place = |pixel: &Rgba<S>, original: &Rgba<S>| {
+        let mut pixel = *pixel;
+        if settings.alpha_mode == AlphaMode::FixedAlpha {
+            pixel.0[3] = original.0[3];
+        }
+        pixel
+    };
+
+    // Sort the pixels at `coords`, splitting them up first if the span is too long
+    let sort_span =
+        |output: &mut ImageBuffer<Rgba<S>, Vec<S>>, coords: &[(u32, u32)], rng: &mut Rng| {
+            for chunk in split_span(coords.len(), &settings, rng) {
+                let coords = &coords[chunk];
+                if coords.len() < settings.min_span_length as usize {
+                    continue;
+                }
+                let mut span = coords
+                    .iter()
+                    .map(|&(x, y)| {
+                        let pixel = *img.get_pixel(x, y);
+                        let secondary = settings.secondary_key.map_or(0.0, |k| sort_key(&pixel, k));
+                        (sort_key(&pixel, settings.sort_key), secondary, pixel)
+                    })
+                    .collect::<Vec<_>>();
+                let compare = |a: &(f32, f32, _), b: &(f32, f32, _)| {
+                    a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+                };
+                match (settings.stable_sort, &settings.order) {
+                    (true, Order::Ascending) => span.sort_by(compare),
+                    // Compared backwards rather than reversed, so ties stay in order
+                    (true, Order::Descending) => span.sort_by(|a, b| compare(b, a)),
+                    (false, order) => {
+                        span.sort_unstable_by(compare);
+                        if *order == Order::Descending {
+                            span.reverse();
                         }
-                        span_start = x;
                     }
                 }
+                for (&(x, y), (_, _, pixel)) in coords.iter().zip(&span) {
+                    output.put_pixel(x, y, place(pixel, img.get_pixel(x, y)));
+                }
+            }
+        };
+
+    for_each_path(&settings, (w, h), &gradients, |line, path| {
+        let mut rng = Rng::for_line(settings.seed, line);
+        let mut span_start = 0;
+        for (i, &(x, y)) in path.iter().enumerate() {
+            let pixel = img.get_pixel(x, y);
+            if is_span_break(x, y, pixel) || i == path.len() - 1 {
+                if span_start + 1 < i {
+                    sort_span(&mut output, &path[span_start..i], &mut rng);
+                }
+                // Fixed pixels don't start the next span, so they never move
+                span_start = if is_fixed(x, y, pixel) { i + 1 } else { i };
+            }
+        }
+    });
+
+    if let Some(weights) = weights {
+        for (x, y, pixel) in output.enumerate_pixels_mut() {
+            let weight = weights[(y * w + x) as usize];
+            *pixel = settings.blend.apply(img.get_pixel(x, y), pixel, weight);
+        }
+    }
+
+    output
+}
+
+/// Lightness gradient (x, y) at each pixel, from a Sobel filter over `lightness` (0-1, row by row)
+fn sobel(lightness: &[f32], w: u32, h: u32) -> Vec<(f32, f32)> {
+    let (w, h) = (w as i64, h as i64);
+    let at = |x: i64, y: i64| lightness[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize];
+    (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let l = |dx, dy| at(x + dx, y + dy);
+            let gx = l(1, -1) + 2.0 * l(1, 0) + l(1, 1) - l(-1, -1) - 2.0 * l(-1, 0) - l(-1, 1);
+            let gy = l(-1, 1) + 2.0 * l(0, 1) + l(1, 1) - l(-1, -1) - 2.0 * l(0, -1) - l(1, -1);
+            (gx, gy)
+        })
+        .collect()
+}
+
+/// Outlines where the gradient is strong, thinned to one pixel by only keeping the strongest
+/// point across each edge, Canny style
+fn edge_map(gradients: &[(f32, f32)], w: u32, h: u32, sensitivity: u8) -> Vec<bool> {
+    let (w, h) = (w as i64, h as i64);
+    let at = |values: &[f32], x: i64, y: i64| {
+        values[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize]
+    };
+    let magnitudes = gradients
+        .iter()
+        .map(|(gx, gy)| gx.hypot(*gy))
+        .collect::<Vec<_>>();
+
+    // A full-contrast straight edge has a gradient of 4, and corners go up to 4 * sqrt(2). With no
+    // sensitivity the threshold is 4, so only the sharpest corners get through.
+    let threshold = (255 - sensitivity) as f32 / 255.0 * 4.0;
+    (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .zip(gradients.iter().zip(&magnitudes))
+        .map(|((x, y), (&(gx, gy), &magnitude))| {
+            if magnitude <= threshold {
+                return false;
+            }
+            // Neighbours either side across the edge, along the nearest of the four directions
+            let angle = gy.atan2(gx).to_degrees().rem_euclid(180.0);
+            let (dx, dy) = match angle {
+                a if !(22.5..157.5).contains(&a) => (1, 0),
+                a if a < 67.5 => (1, 1),
+                a if a < 112.5 => (0, 1),
+                _ => (-1, 1),
+            };
+            // Strict on one side, so a tie between two pixels keeps just one of them
+            magnitude > at(&magnitudes, x + dx, y + dy)
+                && magnitude >= at(&magnitudes, x - dx, y - dy)
+        })
+        .collect()
+}
+
+/// Average each value with those up to `radius` away horizontally and then vertically, in place
+fn box_blur(values: &mut [f32], w: u32, h: u32, radius: u32) {
+    let blur_line = |values: &mut [f32], start: usize, stride: usize, len: usize| {
+        let line = (0..len)
+            .map(|i| values[start + i * stride])
+            .collect::<Vec<_>>();
+        let mut sums = vec![0.0; len + 1];
+        for i in 0..len {
+            sums[i + 1] = sums[i] + line[i];
+        }
+        for i in 0..len {
+            let (from, to) = (
+                i.saturating_sub(radius as usize),
+                (i + radius as usize + 1).min(len),
+            );
+            values[start + i * stride] = (sums[to] - sums[from]) / (to - from) as f32;
+        }
+    };
+    let (w, h) = (w as usize, h as usize);
+    for y in 0..h {
+        blur_line(values, y * w, 1, w);
+    }
+    for x in 0..w {
+        blur_line(values, x, w, h);
+    }
+}
+
+/// Neighbouring pixels going anticlockwise from the right (with y pointing down)
+const NEIGHBOURS: [(i64, i64); 8] = [
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+
+/// Visit the image as a series of paths, each numbered and covering its pixels in order. Every
+/// pixel is on exactly one path. `gradients` are only used to follow contours.
+fn for_each_path(
+    settings: &SortSettings,
+    (w, h): (u32, u32),
+    gradients: &[(f32, f32)],
+    mut f: impl FnMut(u32, &[(u32, u32)]),
+) {
+    match settings.direction {
+        Direction::Horizontal => {
+            for y in 0..h {
+                f(y, &(0..w).map(|x| (x, y)).collect::<Vec<_>>());
             }
         }
         Direction::Vertical => {
             for x in 0..w {
-                let mut span_start: u32 = 0;
-                for y in 0..h {
-                    let pixel = img.get_pixel(x, y);
-                    let luminance = pixel.to_luma().0[0];
-                    output.put_pixel(x, y, pixel);
-                    if luminance < settings.lower_threshold
-                        || luminance > settings.upper_threshold
-                        || y == h - 1
-                    {
-                        if (span_start as isize) < (y as isize) - 1 {
-                            // Sort the span
-                            let mut span = (span_start..y)
-                                .map(|y| img.get_pixel(x, y))
-                                .collect::<Vec<_>>();
-                            span.sort_unstable_by_key(|p| {
-                                p.0[0] as u32 + p.0[1] as u32 + p.0[2] as u32 + p.0[3] as u32
-                            });
-                            if settings.order == Order::Descending {
-                                span.reverse();
-                            }
-                            for i in span_start..y {
-                                if let Some(pixel) = span.get((i - span_start) as usize) {
-                                    output.put_pixel(x, i, *pixel);
-                                }
-                            }
-                        }
-                        span_start = y;
+                f(x, &(0..h).map(|y| (x, y)).collect::<Vec<_>>());
+            }
+        }
+        Direction::Contour => {
+            let mut visited = vec![false; (w * h) as usize];
+            let mut line = 0;
+            for start in 0..w * h {
+                if !visited[start as usize] {
+                    f(
+                        line,
+                        &trace_contour((start % w, start / w), (w, h), gradients, &mut visited),
+                    );
+                    line += 1;
+                }
+            }
+        }
+        Direction::Wave => {
+            // Past the image height a bigger amplitude only cuts rows into shorter pieces, but
+            // would mean walking far more rows that barely cross the image
+            let wave = Wave {
+                amplitude: settings.wave.amplitude.min(h),
+                ..settings.wave.clone()
+            };
+            let offsets = (0..w).map(|x| wave.offset(x)).collect::<Vec<_>>();
+            let amplitude = wave.amplitude as i64;
+            let mut line = 0;
+            let mut path = vec![];
+            // Rows starting above or below the image still cross it, and are cut up wherever
+            // they leave it
+            for row in -amplitude..h as i64 + amplitude {
+                for x in 0..w {
+                    let y = row + offsets[x as usize];
+                    if (0..h as i64).contains(&y) {
+                        path.push((x, y as u32));
+                    } else if !path.is_empty() {
+                        f(line, &path);
+                        line += 1;
+                        path.clear();
                     }
                 }
+                if !path.is_empty() {
+                    f(line, &path);
+                    line += 1;
+                    path.clear();
+                }
             }
         }
+        Direction::Spiral => f(0, &spiral(w, h)),
+        Direction::Hilbert => {
+            let n = w.max(h).next_power_of_two() as u64;
+            f(0, &curve_in_image(n, (w, h), |d| hilbert(n, d)));
+        }
+        Direction::ZOrder => {
+            let n = w.max(h).next_power_of_two() as u64;
+            f(0, &curve_in_image(n, (w, h), z_order));
+        }
     }
+}
 
-    output
+fn spiral(w: u32, h: u32) -> Vec<(u32, u32)> {
+    let mut path = Vec::with_capacity((w * h) as usize);
+    let (mut left, mut top, mut right, mut bottom) = (0, 0, w as i64 - 1, h as i64 - 1);
+    while left <= right && top <= bottom {
+        path.extend((left..=right).map(|x| (x, top)));
+        path.extend((top + 1..=bottom).map(|y| (right, y)));
+        if top < bottom {
+            path.extend((left..right).rev().map(|x| (x, bottom)));
+        }
+        if left < right {
+            path.extend((top + 1..bottom).rev().map(|y| (left, y)));
+        }
+        (left, top, right, bottom) = (left + 1, top + 1, right - 1, bottom - 1);
+    }
+    path.into_iter()
+        .map(|(x, y)| (x as u32, y as u32))
+        .collect()
+}
+
+/// Point `d` along the Hilbert curve filling an `n` by `n` square, where `n` is a power of two
+fn hilbert(n: u64, mut d: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (d / 2);
+        let ry = 1 & (d ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                (x, y) = (s - 1 - x, s - 1 - y);
+            }
+            (x, y) = (y, x);
+        }
+        x += s * rx;
+        y += s * ry;
+        d /= 4;
+        s *= 2;
+    }
+    (x as u32, y as u32)
+}
+
+/// The points of a space-filling curve over an `n` by `n` square that are inside the image.
+/// Every run of 4^k points starting at a multiple of 4^k fills an aligned square of side 2^k, so
+/// squares entirely outside the image are skipped without walking them.
+fn curve_in_image(
+    n: u64,
+    (w, h): (u32, u32),
+    point: impl Fn(u64) -> (u32, u32),
+) -> Vec<(u32, u32)> {
+    let mut path = Vec::with_capacity((w * h) as usize);
+    let mut runs = vec![(0, n * n)];
+    while let Some((start, len)) = runs.pop() {
+        let (x, y) = point(start);
+        let side = 1 << (len.trailing_zeros() / 2);
+        if x - x % side >= w || y - y % side >= h {
+            continue;
+        }
+        if len == 1 {
+            path.push((x, y));
+        } else {
+            // Reversed, so they come off the stack in curve order
+            let quarter = len / 4;
+            runs.extend((0..4).rev().map(|i| (start + i * quarter, quarter)));
+        }
+    }
+    path
+}
+
+/// Point `d` in Z-order, from alternate bits of `d`
+fn z_order(d: u64) -> (u32, u32) {
+    let bits = |mut v: u64| {
+        let mut out = 0;
+        for i in 0..32 {
+            out |= (v & 1) << i;
+            v >>= 2;
+        }
+        out as u32
+    };
+    (bits(d), bits(d >> 1))
+}
+
+/// Follow the contour from `start`, at right angles to the gradient, until the path leaves the
+/// image or runs into a pixel that's already on a path
+fn trace_contour(
+    start: (u32, u32),
+    (w, h): (u32, u32),
+    gradients: &[(f32, f32)],
+    visited: &mut [bool],
+) -> Vec<(u32, u32)> {
+    let (mut x, mut y) = (start.0 as i64, start.1 as i64);
+    let (w, h) = (w as i64, h as i64);
+    visited[(y * w + x) as usize] = true;
+    let mut path = vec![start];
+    let mut heading = (1.0, 0.0);
+    loop {
+        let (gx, gy) = gradients[(y * w + x) as usize];
+        // Flat areas have no contour, so keep going the same way
+        let mut tangent = if gx.hypot(gy) > 1e-3 {
+            (-gy, gx)
+        } else {
+            heading
+        };
+        // Contours run both ways, so don't turn back on ourselves
+        if tangent.0 * heading.0 + tangent.1 * heading.1 < 0.0 {
+            tangent = (-tangent.0, -tangent.1);
+        }
+        let octant = (tangent.1.atan2(tangent.0) / FRAC_PI_4)
+            .round()
+            .rem_euclid(8.0) as usize;
+        let (dx, dy) = NEIGHBOURS[octant];
+        let (nx, ny) = (x + dx, y + dy);
+        if nx < 0 || ny < 0 || nx >= w || ny >= h || visited[(ny * w + nx) as usize] {
+            break;
+        }
+        visited[(ny * w + nx) as usize] = true;
+        path.push((nx as u32, ny as u32));
+        (x, y) = (nx, ny);
+        heading = tangent;
+    }
+    path
+}
+
+/// Cut a span of `len` pixels into chunks no longer than the maximum span length
+fn split_span(len: usize, settings: &SortSettings, rng: &mut Rng) -> Vec<Range<usize>> {
+    let max = match settings.max_span_length as usize {
+        0 => len,
+        max => max.min(len),
+    };
+    let min = (settings.min_span_length as usize).clamp(1, max);
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < len {
+        let chunk_len = match settings.span_split {
+            SpanSplit::Fixed => max,
+            SpanSplit::Random => rng.range(min..max + 1),
+        };
+        let end = (start + chunk_len).min(len);
+        chunks.push(start..end);
+        start = end;
+    }
+    chunks
 }
 
 pub trait ImageToBytes {
@@ -125,6 +1201,60 @@ impl ImageToBytes for ImageBuffer<Rgba<u8>, Vec<u8>> {
     }
 }
 
+/// Encode an image, keeping as much of its bit depth as `format` allows
+pub fn encode_img(img: &DynamicImage, format: ImageFormat) -> ImageResult<Vec<u8>> {
+    let mut buf: BufWriter<Cursor<Vec<u8>>> = BufWriter::new(Cursor::new(vec![]));
+    match convert_for_format(img, format) {
+        Some(converted) => converted.write_to(&mut buf, format),
+        None => img.write_to(&mut buf, format),
+    }?;
+    buf.flush()?;
+    Ok(buf.get_ref().to_owned().into_inner())
+}
+
+/// Convert to the highest bit depth `format` can store, or `None` if it can store `img` as it is
+pub fn convert_for_format(img: &DynamicImage, format: ImageFormat) -> Option<DynamicImage> {
+    match (format, img.color()) {
+        (ImageFormat::Png | ImageFormat::Tiff, ColorType::Rgba8 | ColorType::Rgba16) => None,
+        (
+            ImageFormat::Png | ImageFormat::Tiff,
+            ColorType::L16
+            | ColorType::La16
+            | ColorType::Rgb16
+            | ColorType::Rgb32F
+            | ColorType::Rgba32F,
+        ) => Some(DynamicImage::ImageRgba16(img.to_rgba16())),
+        (ImageFormat::OpenExr, ColorType::Rgba32F) => None,
+        (ImageFormat::OpenExr, _) => Some(DynamicImage::ImageRgba32F(img.to_rgba32f())),
+        (_, ColorType::Rgba8) => None,
+        _ => Some(DynamicImage::ImageRgba8(img.to_rgba8())),
+    }
+}
+
+/// Size of the image as it's sorted, after EXIF rotation, from the header alone
+pub fn oriented_dimensions(img_data: &Vec<u8>) -> Option<(u32, u32)> {
+    let (w, h) = image::io::Reader::new(Cursor::new(img_data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    match get_orientation(img_data) {
+        Some(6 | 8) => Some((h, w)),
+        _ => Some((w, h)),
+    }
+}
+
+/// Decode an image, rotated the way its EXIF orientation says it should be shown
+pub fn load_img(img_data: &Vec<u8>) -> ImageResult<DynamicImage> {
+    let img = image::load_from_memory(img_data)?;
+    Ok(match get_orientation(img_data) {
+        Some(3) => img.rotate180(),
+        Some(6) => img.rotate90(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    })
+}
+
 pub fn get_orientation(img_data: &Vec<u8>) -> Option<u32> {
     let cursor = Cursor::new(img_data);
     let mut file_reader = BufReader::new(cursor);
@@ -141,3 +1271,240 @@ pub fn get_orientation(img_data: &Vec<u8>) -> Option<u32> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span_settings(min: u32, max: u32, span_split: SpanSplit) -> SortSettings {
+        SortSettings {
+            min_span_length: min,
+            max_span_length: max,
+            span_split,
+            ..SortSettings::default()
+        }
+    }
+
+    /// Chunks must cover `0..len` in order without gaps or overlaps
+    fn assert_covers(chunks: &[Range<usize>], len: usize) {
+        let mut next = 0;
+        for chunk in chunks {
+            assert_eq!(chunk.start, next);
+            assert!(chunk.end > chunk.start);
+            next = chunk.end;
+        }
+        assert_eq!(next, len);
+    }
+
+    #[test]
+    fn split_span_fixed() {
+        let settings = span_settings(0, 4, SpanSplit::Fixed);
+        let chunks = split_span(10, &settings, &mut Rng::for_line(0, 0));
+        assert_eq!(chunks, vec![0..4, 4..8, 8..10]);
+    }
+
+    #[test]
+    fn split_span_without_maximum() {
+        let settings = span_settings(0, 0, SpanSplit::Random);
+        let chunks = split_span(10, &settings, &mut Rng::for_line(0, 0));
+        assert_eq!(chunks, vec![0..10]);
+    }
+
+    #[test]
+    fn split_span_random() {
+        let settings = span_settings(3, 8, SpanSplit::Random);
+        for line in 0..50 {
+            let chunks = split_span(100, &settings, &mut Rng::for_line(1, line));
+            assert_covers(&chunks, 100);
+            // Only the last chunk can be cut short by the end of the span
+            for chunk in &chunks[..chunks.len() - 1] {
+                assert!((3..=8).contains(&chunk.len()));
+            }
+            let again = split_span(100, &settings, &mut Rng::for_line(1, line));
+            assert_eq!(chunks, again);
+        }
+    }
+
+    /// Every pixel must be on exactly one path
+    fn assert_paths_cover(settings: &SortSettings, (w, h): (u32, u32), gradients: &[(f32, f32)]) {
+        let mut seen = vec![0; (w * h) as usize];
+        for_each_path(settings, (w, h), gradients, |_, path| {
+            for &(x, y) in path {
+                assert!(x < w && y < h);
+                seen[(y * w + x) as usize] += 1;
+            }
+        });
+        assert!(seen.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn wave_rows_cover_every_pixel_once() {
+        for shape in [WaveShape::Sine, WaveShape::ZigZag, WaveShape::Bezier] {
+            for amplitude in [0, 5, 100, u32::MAX] {
+                let settings = SortSettings {
+                    direction: Direction::Wave,
+                    wave: Wave {
+                        shape: shape.clone(),
+                        amplitude,
+                        wavelength: 17,
+                        phase: 90,
+                    },
+                    ..SortSettings::default()
+                };
+                assert_paths_cover(&settings, (137, 61), &[]);
+            }
+        }
+    }
+
+    #[test]
+    fn contours_cover_every_pixel_once() {
+        let settings = SortSettings {
+            direction: Direction::Contour,
+            ..SortSettings::default()
+        };
+        for (w, h) in [(37, 23), (1, 9), (1, 1)] {
+            // Rings and stripes, so contours curve, close on themselves and run into flat areas
+            let lightness = (0..w * h)
+                .map(|i| {
+                    let (x, y) = ((i % w) as f32, (i / w) as f32);
+                    let ring = ((x - 15.0).powi(2) + (y - 10.0).powi(2)).sqrt() / 4.0;
+                    if x < 28.0 {
+                        ring.sin() * 0.5 + 0.5
+                    } else {
+                        (y / 3.0).floor() % 2.0
+                    }
+                })
+                .collect::<Vec<_>>();
+            assert_paths_cover(&settings, (w, h), &sobel(&lightness, w, h));
+        }
+    }
+
+    #[test]
+    fn spiral_and_curves_cover_every_pixel_once() {
+        for direction in [Direction::Spiral, Direction::Hilbert, Direction::ZOrder] {
+            for size in [(137, 61), (61, 137), (64, 64), (1000, 3), (1, 1)] {
+                let settings = SortSettings {
+                    direction: direction.clone(),
+                    ..SortSettings::default()
+                };
+                assert_paths_cover(&settings, size, &[]);
+            }
+        }
+    }
+
+    #[test]
+    fn curves_skip_outside_the_image_in_order() {
+        let (w, h) = (137, 61);
+        let n = 256;
+        let filtered = |point: &dyn Fn(u64) -> (u32, u32)| {
+            (0..n * n)
+                .map(point)
+                .filter(|&(x, y)| x < w && y < h)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            curve_in_image(n, (w, h), |d| hilbert(n, d)),
+            filtered(&|d| hilbert(n, d))
+        );
+        assert_eq!(curve_in_image(n, (w, h), z_order), filtered(&z_order));
+    }
+
+    #[test]
+    fn hilbert_steps_between_neighbours() {
+        let n = 64;
+        let path = curve_in_image(n, (64, 64), |d| hilbert(n, d));
+        for pair in path.windows(2) {
+            let ((x1, y1), (x2, y2)) = (pair[0], pair[1]);
+            assert_eq!(x1.abs_diff(x2) + y1.abs_diff(y2), 1);
+        }
+    }
+
+    #[test]
+    fn huge_regions_contain_points_without_overflow() {
+        let region = Region {
+            x: 10,
+            y: 10,
+            width: u32::MAX,
+            height: u32::MAX,
+        };
+        assert!(region.contains(10, u32::MAX));
+        assert!(!region.contains(9, 10));
+    }
+
+    #[test]
+    fn out_of_range_settings_are_invalid() {
+        assert!(SortSettings::default().is_valid());
+        let mut settings = SortSettings::default();
+        settings.mask.bands.clear();
+        assert!(!settings.is_valid());
+        let settings = SortSettings {
+            regions: vec![Region {
+                x: 10,
+                y: 0,
+                width: u32::MAX,
+                height: 1,
+            }],
+            ..SortSettings::default()
+        };
+        assert!(!settings.is_valid());
+        let mut settings = SortSettings::default();
+        settings.wave.amplitude = u32::MAX;
+        assert!(!settings.is_valid());
+    }
+
+    #[test]
+    fn blending_leaves_unsorted_pixels_alone() {
+        let img = ImageBuffer::from_fn(16, 16, |x, y| {
+            let v = ((x * 37 + y * 101) % 256) as u8;
+            Rgba([v, v.wrapping_mul(3), 255 - v, 255])
+        });
+        // Feathering only fades in the sorted pixels, so it mustn't change which are left alone
+        for feather in [0, 1, 4] {
+            let settings = SortSettings {
+                regions: vec![Region {
+                    x: 4,
+                    y: 4,
+                    width: 8,
+                    height: 8,
+                }],
+                blend: Blend {
+                    mode: BlendMode::Difference,
+                    opacity: 100,
+                },
+                feather,
+                ..SortSettings::default()
+            };
+            let sorted = sort_img(DynamicImage::ImageRgba8(img.clone()), settings.clone());
+            let sorted = sorted.as_rgba8().unwrap();
+            for (x, y, pixel) in img.enumerate_pixels() {
+                if !settings.regions[0].contains(x, y) {
+                    assert_eq!(sorted.get_pixel(x, y), pixel);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_images_are_saved_as_png() {
+        let opaque = DynamicImage::new_rgb8(1, 1);
+        assert_eq!(OutputFormat::for_image(&opaque), OutputFormat::Jpeg);
+        let transparent = DynamicImage::new_rgba8(1, 1);
+        assert_eq!(OutputFormat::for_image(&transparent), OutputFormat::Png);
+        let deep = DynamicImage::new_rgb16(1, 1);
+        assert_eq!(OutputFormat::for_image(&deep), OutputFormat::Png);
+    }
+
+    #[test]
+    fn sorting_with_a_seed_is_reproducible() {
+        let img = ImageBuffer::from_fn(32, 16, |x, y| {
+            let v = ((x * 37 + y * 101) % 256) as u8;
+            Rgba([v, v.wrapping_mul(3), 255 - v, 255])
+        });
+        let settings = SortSettings {
+            seed: 1234,
+            ..span_settings(2, 6, SpanSplit::Random)
+        };
+        let sort = || sort_img(DynamicImage::ImageRgba8(img.clone()), settings.clone());
+        assert_eq!(sort(), sort());
+    }
+}
@@ -1,45 +1,172 @@
+use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::rc::Rc;
 
-use base64::engine::{general_purpose::STANDARD as b64, Engine};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as b64url;
+use base64::engine::Engine;
+use gloo::events::EventListener;
 use gloo::file::callbacks::FileReader;
-use gloo::file::File;
-use web_sys::{DragEvent, Event, FileList, HtmlInputElement};
+use gloo::file::{Blob, File, ObjectUrl};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    DataTransfer, DragEvent, Element, Event, FileList, HtmlAnchorElement, HtmlInputElement,
+    Response,
+};
 use yew::html::TargetCast;
 use yew::prelude::*;
 use yew::{html, Callback, Component, Context, Html};
-use yew_agent::{Bridge, Bridged};
 use yew_icons::{Icon, IconId};
 
-use crate::agent::{Worker, WorkerInput, WorkerOutput, WorkerStatus};
-use crate::components::{FullscreenImage, Header};
-use crate::img::{Direction, Order, SortSettings};
+use crate::agent::{RawImage, SortedImage, WorkerBridge, WorkerInput, WorkerOutput, WorkerStatus};
+use crate::animation::{AnimationFormat, AnimationSettings, Keyframe};
+use crate::archive::create_zip;
+use crate::components::{Comparison, FullscreenImage, Header, ImageSource, View};
+use crate::img::{
+    AlphaMode, BlendMode, ColourSpace, Direction, MaskBand, MaskCombine, MaskSource, Order,
+    OutputFormat, Preview, Region, SortKey, SortSettings, SpanSplit, WaveShape, MAX_WAVELENGTH,
+    MAX_WAVE_AMPLITUDE,
+};
 
 pub mod agent;
+pub mod animation;
+mod archive;
 mod components;
-mod img;
+pub mod img;
+mod rng;
 
-#[derive(Clone, Debug, Default, PartialEq)]
+/// Object URL for some image data, revoked once the last clone of it is dropped
+#[derive(Clone)]
+pub struct BlobUrl(ObjectUrl);
+
+impl BlobUrl {
+    pub fn new(data: &[u8], mime_type: &str) -> Self {
+        BlobUrl(ObjectUrl::from(Blob::new_with_options(
+            data,
+            Some(mime_type),
+        )))
+    }
+}
+
+impl std::ops::Deref for BlobUrl {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for BlobUrl {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl std::fmt::Debug for BlobUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ImageDetails {
     pub name: String,
     pub file_type: String,
     pub data: Vec<u8>,
+    pub url: BlobUrl,
+    /// Size after EXIF rotation, if the header could be read
+    pub dimensions: Option<(u32, u32)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchStatus {
+    Reading,
+    Queued,
+    Processing,
+    Done(SortedImage, BlobUrl),
+    Failed(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchItem {
+    pub id: usize,
+    pub name: String,
+    pub file_type: String,
+    pub data: Vec<u8>,
+    pub status: BatchStatus,
+}
+
+/// Work that has been sent to the worker, in the order it was sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Job {
+    Preview,
+    /// Encoding the previewed image to download it
+    Save,
+    Batch(usize),
 }
 
 pub enum Msg {
     // Image
     LoadImage(Option<File>),
+    LoadImages(Vec<File>),
+    /// A `blob:` or `data:` URL dragged in from another tab
+    LoadImageUrl(String),
     ImageLoaded(String, String, Vec<u8>),
+    BatchImageLoaded(usize, Vec<u8>),
     SetLowerThreshold(u8),
     SetUpperThreshold(u8),
+    SetMaskSource(MaskSource),
+    SetMaskColour([u8; 3]),
+    SelectMaskBand(usize),
+    AddMaskBand,
+    RemoveMaskBand,
+    SetMaskCombine(MaskCombine),
+    ToggleMaskInvert,
+    SetEdgeSensitivity(u8),
+    SetFeather(u32),
+    ToggleSelectRegions,
+    /// Corners of a region being dragged out, in image coordinates
+    StartRegion((u32, u32)),
+    DragRegion((u32, u32)),
+    EndRegion,
+    ClearRegions,
+    TogglePreview(Preview),
     SetDirection(Direction),
+    SetWaveShape(WaveShape),
+    SetWaveAmplitude(u32),
+    SetWavelength(u32),
+    SetWavePhase(u16),
     SetOrder(Order),
+    SetAlphaMode(AlphaMode),
+    SetSortKey(SortKey),
+    SetSecondaryKey(Option<SortKey>),
+    ToggleStableSort,
+    SetColourSpace(ColourSpace),
+    SetBlendMode(BlendMode),
+    SetBlendOpacity(u8),
+    SetMinSpanLength(u32),
+    SetMaxSpanLength(u32),
+    SetSpanSplit(SpanSplit),
+    SetSeed(u64),
+    RerollSeed,
     SettingsChanged,
-    ToggleShowOriginal,
+    SetView(View),
     Reset,
     ClearImage,
     ToggleZoom,
+    // Animation
+    SetFrameCount(u32),
+    SetFrameDelay(u32),
+    SetKeyframeFrame(u32),
+    SetAnimationFormat(AnimationFormat),
+    AddKeyframe,
+    RemoveKeyframe(usize),
+    RenderAnimation,
+    // Batch
+    RunBatch,
+    SaveAll,
     // Worker
+    SavePreview,
     RunWorker,
     WorkerMsg(WorkerOutput),
 }
@@ -48,13 +175,32 @@ pub struct App {
     // Image
     img: Option<ImageDetails>,
     img_reader: Option<FileReader>,
+    _paste_listener: EventListener,
     sort_settings: SortSettings,
+    /// Index of the mask band being edited
+    selected_band: usize,
     zoomed: bool,
-    sorted_data: Option<Vec<u8>>,
-    show_original: bool,
+    sorted_data: Option<(SortedImage, BlobUrl)>,
+    /// Still images are previewed unencoded
+    sorted_pixels: Option<Rc<RawImage>>,
+    view: View,
+    preview: Preview,
+    selecting_regions: bool,
+    region_drag: Option<((u32, u32), (u32, u32))>,
+    // Animation
+    animation: AnimationSettings,
+    keyframe_frame: u32,
+    // Batch
+    batch: Vec<BatchItem>,
+    batch_readers: Vec<FileReader>,
+    batch_settings: SortSettings,
+    next_batch_id: usize,
+    /// Kept so the last download stays available until the next one replaces it
+    download_url: Option<BlobUrl>,
     // Worker
-    worker: Box<dyn Bridge<Worker>>,
+    worker: WorkerBridge,
     worker_status: Option<WorkerStatus>,
+    jobs: VecDeque<Job>,
 }
 
 impl Component for App {
@@ -62,21 +208,46 @@ impl Component for App {
     type Properties = ();
 
     fn create(ctx: &Context<Self>) -> Self {
-        let cb = {
+        let worker = WorkerBridge::new(ctx.link().callback(Self::Message::WorkerMsg));
+        let paste_listener = {
             let link = ctx.link().clone();
-            move |e| link.send_message(Self::Message::WorkerMsg(e))
+            EventListener::new(&gloo::utils::document(), "paste", move |e| {
+                // Read off a plain event, as `ClipboardEvent` is still an unstable API in web-sys
+                let files = js_sys::Reflect::get(e, &JsValue::from_str("clipboardData"))
+                    .ok()
+                    .and_then(|data| data.dyn_into::<DataTransfer>().ok())
+                    .and_then(|data| data.files())
+                    .filter(|files| files.length() > 0);
+                // Anything else is left to paste as normal, e.g. into a number input
+                if files.is_some() {
+                    link.send_message(Self::load_image(files));
+                }
+            })
         };
-        let worker = Worker::bridge(Rc::new(cb));
 
         Self {
             img: None,
             img_reader: None,
-            sort_settings: SortSettings::default(),
+            _paste_listener: paste_listener,
+            sort_settings: settings_from_url().unwrap_or_default(),
+            selected_band: 0,
             zoomed: false,
             sorted_data: None,
-            show_original: false,
+            sorted_pixels: None,
+            view: View::Sorted,
+            preview: Preview::Sorted,
+            selecting_regions: false,
+            region_drag: None,
+            animation: AnimationSettings::default(),
+            keyframe_frame: 1,
+            batch: vec![],
+            batch_readers: vec![],
+            batch_settings: SortSettings::default(),
+            next_batch_id: 0,
+            download_url: None,
             worker,
             worker_status: None,
+            jobs: VecDeque::new(),
         }
     }
 
@@ -84,12 +255,15 @@ impl Component for App {
         match msg {
             Msg::LoadImage(file) => {
                 self.sorted_data = None;
+                self.sorted_pixels = None;
+                // A single image replaces any batch, so "Sort all" can't run on files no longer shown
+                self.batch.clear();
+                self.batch_readers.clear();
                 if let Some(file) = file {
                     let file_name = file.name();
                     let file_type = file.raw_mime_type();
 
                     let link = ctx.link().clone();
-                    let file_name = file_name;
 
                     self.img_reader =
                         Some(gloo::file::callbacks::read_as_bytes(&file, move |res| {
@@ -104,8 +278,51 @@ impl Component for App {
                     self.img_reader = None;
                 }
             }
+            Msg::LoadImages(files) => {
+                self.sorted_data = None;
+                self.sorted_pixels = None;
+                self.img = None;
+                self.batch.clear();
+                self.batch_readers.clear();
+                for file in files {
+                    let id = self.next_batch_id;
+                    self.next_batch_id += 1;
+                    self.batch.push(BatchItem {
+                        id,
+                        name: file.name(),
+                        file_type: file.raw_mime_type(),
+                        data: vec![],
+                        status: BatchStatus::Reading,
+                    });
+
+                    let link = ctx.link().clone();
+                    self.batch_readers
+                        .push(gloo::file::callbacks::read_as_bytes(&file, move |res| {
+                            link.send_message(Msg::BatchImageLoaded(
+                                id,
+                                res.expect("failed to read file"),
+                            ))
+                        }));
+                }
+            }
+            Msg::LoadImageUrl(url) => {
+                let link = ctx.link().clone();
+                wasm_bindgen_futures::spawn_local(async move {
+                    match fetch_blob(&url).await {
+                        Ok(blob) => {
+                            let file_type = blob.raw_mime_type();
+                            let file =
+                                File::new_with_options("image", blob, Some(&file_type), None);
+                            link.send_message(Msg::LoadImage(Some(file)));
+                        }
+                        Err(err) => log::error!("Failed to load dropped image: {:?}", err),
+                    }
+                });
+            }
             Msg::ImageLoaded(file_name, file_type, data) => {
                 self.img = Some(ImageDetails {
+                    dimensions: img::oriented_dimensions(&data),
+                    url: BlobUrl::new(&data, &file_type),
                     data,
                     file_type,
                     name: file_name,
@@ -113,59 +330,380 @@ impl Component for App {
                 self.img_reader = None;
                 ctx.link().send_message(Msg::RunWorker);
             }
+            Msg::BatchImageLoaded(id, data) => {
+                if let Some(item) = self.batch.iter_mut().find(|item| item.id == id) {
+                    item.data = data;
+                    item.status = BatchStatus::Queued;
+                    // Preview the first image to be loaded so settings can be tuned on it
+                    if self.img.is_none() {
+                        self.img = Some(ImageDetails {
+                            name: item.name.clone(),
+                            file_type: item.file_type.clone(),
+                            data: item.data.clone(),
+                            url: BlobUrl::new(&item.data, &item.file_type),
+                            dimensions: img::oriented_dimensions(&item.data),
+                        });
+                        ctx.link().send_message(Msg::RunWorker);
+                    }
+                }
+                if self
+                    .batch
+                    .iter()
+                    .all(|item| item.status != BatchStatus::Reading)
+                {
+                    self.batch_readers.clear();
+                    ctx.link().send_message(Msg::RunBatch);
+                }
+            }
             Msg::SetLowerThreshold(value) => {
-                self.sort_settings.lower_threshold = value;
-                if self.sort_settings.upper_threshold <= self.sort_settings.lower_threshold {
-                    self.sort_settings.upper_threshold = self.sort_settings.lower_threshold;
+                let band = &mut self.sort_settings.mask.bands[self.selected_band];
+                band.lower_threshold = value;
+                // Hue wraps around, so the thresholds can cross
+                if band.source != MaskSource::Hue && band.upper_threshold <= band.lower_threshold {
+                    band.upper_threshold = band.lower_threshold;
                 }
             }
             Msg::SetUpperThreshold(value) => {
-                self.sort_settings.upper_threshold = value;
-                if self.sort_settings.lower_threshold >= self.sort_settings.upper_threshold {
-                    self.sort_settings.lower_threshold = self.sort_settings.upper_threshold;
+                let band = &mut self.sort_settings.mask.bands[self.selected_band];
+                band.upper_threshold = value;
+                if band.source != MaskSource::Hue && band.lower_threshold >= band.upper_threshold {
+                    band.lower_threshold = band.upper_threshold;
+                }
+            }
+            Msg::SetMaskSource(source) => {
+                let band = &mut self.sort_settings.mask.bands[self.selected_band];
+                band.source = source;
+                if band.lower_threshold > band.upper_threshold && !band.wraps() {
+                    std::mem::swap(&mut band.lower_threshold, &mut band.upper_threshold);
+                }
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetMaskColour(colour) => {
+                self.sort_settings.mask.bands[self.selected_band].source =
+                    MaskSource::Distance(colour);
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SelectMaskBand(index) => {
+                self.selected_band = index;
+            }
+            Msg::AddMaskBand => {
+                self.sort_settings.mask.bands.push(MaskBand::default());
+                self.selected_band = self.sort_settings.mask.bands.len() - 1;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::RemoveMaskBand => {
+                let bands = &mut self.sort_settings.mask.bands;
+                if bands.len() > 1 {
+                    bands.remove(self.selected_band);
+                    self.selected_band = self.selected_band.min(bands.len() - 1);
+                    ctx.link().send_message(Msg::SettingsChanged)
+                }
+            }
+            Msg::SetMaskCombine(combine) => {
+                self.sort_settings.mask.combine = combine;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::ToggleMaskInvert => {
+                self.sort_settings.mask.invert = !self.sort_settings.mask.invert;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetFeather(value) => {
+                self.sort_settings.feather = value;
+            }
+            Msg::SetEdgeSensitivity(value) => {
+                self.sort_settings.edge_sensitivity = value;
+            }
+            Msg::ToggleSelectRegions => {
+                self.selecting_regions = !self.selecting_regions;
+                self.region_drag = None;
+            }
+            Msg::StartRegion(point) => {
+                self.region_drag = Some((point, point));
+            }
+            Msg::DragRegion(point) => {
+                if let Some((_, end)) = &mut self.region_drag {
+                    *end = point;
+                }
+            }
+            Msg::EndRegion => {
+                if let Some((start, end)) = self.region_drag.take() {
+                    let region = region_between(start, end);
+                    // Ignore clicks that didn't drag out an area
+                    if region.width > 1 && region.height > 1 {
+                        self.sort_settings.regions.push(region);
+                        ctx.link().send_message(Msg::SettingsChanged)
+                    }
                 }
             }
+            Msg::ClearRegions => {
+                self.sort_settings.regions.clear();
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::TogglePreview(preview) => {
+                self.preview = if self.preview == preview {
+                    Preview::Sorted
+                } else {
+                    preview
+                };
+                ctx.link().send_message(Msg::RunWorker)
+            }
             Msg::SetDirection(direction) => {
                 self.sort_settings.direction = direction;
                 ctx.link().send_message(Msg::SettingsChanged)
             }
+            Msg::SetWaveShape(shape) => {
+                self.sort_settings.wave.shape = shape;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetWaveAmplitude(amplitude) => {
+                self.sort_settings.wave.amplitude = amplitude.min(MAX_WAVE_AMPLITUDE);
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetWavelength(wavelength) => {
+                self.sort_settings.wave.wavelength = wavelength.clamp(1, MAX_WAVELENGTH);
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetWavePhase(phase) => {
+                self.sort_settings.wave.phase = phase;
+            }
             Msg::SetOrder(order) => {
                 self.sort_settings.order = order;
                 ctx.link().send_message(Msg::SettingsChanged)
             }
+            Msg::SetAlphaMode(alpha_mode) => {
+                self.sort_settings.alpha_mode = alpha_mode;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetSortKey(sort_key) => {
+                self.sort_settings.sort_key = sort_key;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetSecondaryKey(secondary_key) => {
+                self.sort_settings.secondary_key = secondary_key;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::ToggleStableSort => {
+                self.sort_settings.stable_sort = !self.sort_settings.stable_sort;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetColourSpace(colour_space) => {
+                self.sort_settings.colour_space = colour_space;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetBlendMode(mode) => {
+                self.sort_settings.blend.mode = mode;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetBlendOpacity(opacity) => {
+                self.sort_settings.blend.opacity = opacity;
+            }
+            Msg::SetMinSpanLength(length) => {
+                self.sort_settings.min_span_length = length;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetMaxSpanLength(length) => {
+                self.sort_settings.max_span_length = length;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetSpanSplit(span_split) => {
+                self.sort_settings.span_split = span_split;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::SetSeed(seed) => {
+                self.sort_settings.seed = seed;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
+            Msg::RerollSeed => {
+                self.sort_settings.seed = (js_sys::Math::random() * u32::MAX as f64) as u64;
+                ctx.link().send_message(Msg::SettingsChanged)
+            }
             Msg::Reset => {
                 self.sort_settings = SortSettings::default();
-                ctx.link().send_message(Msg::RunWorker);
+                self.selected_band = 0;
+                ctx.link().send_message(Msg::SettingsChanged);
             }
             Msg::ClearImage => {
                 self.img = None;
+                self.batch.clear();
+                self.batch_readers.clear();
             }
             Msg::ToggleZoom => {
                 self.zoomed = !self.zoomed;
             }
-            Msg::SettingsChanged => ctx.link().send_message(Msg::RunWorker),
-            Msg::ToggleShowOriginal => {
-                self.show_original = !self.show_original;
+            Msg::SettingsChanged => {
+                save_settings_to_url(&self.sort_settings);
+                ctx.link().send_message(Msg::RunWorker)
+            }
+            Msg::SetView(view) => {
+                self.view = view;
+            }
+            // Animation
+            Msg::SetFrameCount(value) => {
+                self.animation.frame_count = value.clamp(2, 600);
+                self.keyframe_frame = self.keyframe_frame.min(self.animation.frame_count);
+            }
+            Msg::SetFrameDelay(value) => {
+                self.animation.frame_delay_ms = value.clamp(10, 10000);
+            }
+            Msg::SetKeyframeFrame(value) => {
+                self.keyframe_frame = value.clamp(1, self.animation.frame_count);
+            }
+            Msg::SetAnimationFormat(format) => {
+                self.animation.format = format;
+            }
+            Msg::AddKeyframe => {
+                self.animation.set_keyframe(Keyframe {
+                    frame: self.keyframe_frame,
+                    settings: self.sort_settings.clone(),
+                });
+            }
+            Msg::RemoveKeyframe(index) => {
+                self.animation.keyframes.remove(index);
+            }
+            Msg::RenderAnimation => {
+                if let Some(img_details) = &self.img {
+                    self.worker.send(WorkerInput {
+                        img_data: img_details.data.clone(),
+                        settings: self.sort_settings.clone(),
+                        animation: Some(self.animation.clone()),
+                        preview: Preview::Sorted,
+                        raw: false,
+                    });
+                    self.jobs.push_back(Job::Preview);
+                }
+            }
+            // Batch
+            Msg::RunBatch => {
+                self.batch_settings = self.sort_settings.clone();
+                for item in self.batch.iter_mut() {
+                    if item.status == BatchStatus::Reading {
+                        continue;
+                    }
+                    item.status = BatchStatus::Queued;
+                    self.worker.send(WorkerInput {
+                        img_data: item.data.clone(),
+                        settings: self.sort_settings.clone(),
+                        animation: None,
+                        preview: Preview::Sorted,
+                        raw: false,
+                    });
+                    self.jobs.push_back(Job::Batch(item.id));
+                }
+            }
+            Msg::SaveAll => {
+                let outputs = self
+                    .batch
+                    .iter()
+                    .filter_map(|item| match &item.status {
+                        BatchStatus::Done(sorted, _) => Some((
+                            sorted_file_name(&item.name, sorted.format),
+                            item.name.clone(),
+                            sorted.data.as_slice(),
+                        )),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let zip = create_zip(&self.batch_settings, &outputs);
+                self.download(BlobUrl::new(&zip, "application/zip"), "pixel-sorter.zip");
             }
             // Worker
             Msg::RunWorker => {
                 if let Some(img_details) = &self.img {
+                    // Regions from a link can be for a different image
+                    if let Some(dimensions) = img_details.dimensions {
+                        let regions = &mut self.sort_settings.regions;
+                        *regions = regions
+                            .iter()
+                            .filter_map(|r| r.clamped(dimensions))
+                            .collect();
+                    }
                     self.worker.send(WorkerInput {
                         img_data: img_details.data.clone(),
                         settings: self.sort_settings.clone(),
+                        animation: None,
+                        preview: self.preview.clone(),
+                        raw: true,
                     });
+                    self.jobs.push_back(Job::Preview);
+                }
+            }
+            Msg::SavePreview => {
+                if let Some(img_details) = &self.img {
+                    self.worker.send(WorkerInput {
+                        img_data: img_details.data.clone(),
+                        settings: self.sort_settings.clone(),
+                        animation: None,
+                        // Save the sorted image, even while the mask or edges are being previewed
+                        preview: Preview::Sorted,
+                        raw: false,
+                    });
+                    self.jobs.push_back(Job::Save);
                 }
             }
             Msg::WorkerMsg(output) => {
-                // the worker is done!
-                match output {
-                    WorkerOutput::StatusUpdate(status) => {
-                        self.worker_status = Some(status);
-                    }
-                    WorkerOutput::Sorted(img_data) => {
-                        self.sorted_data = Some(img_data);
-                        self.worker_status = None;
+                let job = match output {
+                    WorkerOutput::StatusUpdate(_) => self.jobs.front().copied(),
+                    // the worker is done with this job!
+                    _ => self.jobs.pop_front(),
+                };
+                match job {
+                    Some(Job::Batch(id)) => {
+                        if let Some(item) = self.batch.iter_mut().find(|item| item.id == id) {
+                            item.status = match output {
+                                WorkerOutput::StatusUpdate(_) => BatchStatus::Processing,
+                                WorkerOutput::Sorted(img_data) => {
+                                    let url =
+                                        BlobUrl::new(&img_data.data, img_data.format.mime_type());
+                                    BatchStatus::Done(img_data, url)
+                                }
+                                WorkerOutput::Rendered(_) => {
+                                    unreachable!("batch images are always encoded")
+                                }
+                                WorkerOutput::Failed(err) => BatchStatus::Failed(err),
+                            };
+                        }
                     }
+                    Some(Job::Save) => match output {
+                        WorkerOutput::StatusUpdate(status) => {
+                            self.worker_status = Some(status);
+                        }
+                        WorkerOutput::Sorted(img_data) => {
+                            self.worker_status = None;
+                            if let Some(img_details) = &self.img {
+                                let file_name =
+                                    sorted_file_name(&img_details.name, img_data.format);
+                                let url = BlobUrl::new(&img_data.data, img_data.format.mime_type());
+                                self.download(url, &file_name);
+                            }
+                        }
+                        WorkerOutput::Rendered(_) => {
+                            unreachable!("saved images are always encoded")
+                        }
+                        WorkerOutput::Failed(err) => {
+                            log::error!("Failed to save image: {}", err);
+                            self.worker_status = None;
+                        }
+                    },
+                    _ => match output {
+                        WorkerOutput::StatusUpdate(status) => {
+                            self.worker_status = Some(status);
+                        }
+                        WorkerOutput::Sorted(img_data) => {
+                            let url = BlobUrl::new(&img_data.data, img_data.format.mime_type());
+                            self.sorted_data = Some((img_data, url));
+                            self.sorted_pixels = None;
+                            self.worker_status = None;
+                        }
+                        WorkerOutput::Rendered(pixels) => {
+                            self.sorted_pixels = Some(Rc::new(pixels));
+                            self.sorted_data = None;
+                            self.worker_status = None;
+                        }
+                        WorkerOutput::Failed(err) => {
+                            log::error!("Failed to sort image: {}", err);
+                            self.worker_status = None;
+                        }
+                    },
                 }
             }
         }
@@ -181,7 +719,83 @@ impl Component for App {
                     <div class={classes!("controls-container")}>
                         <div class={classes!("controls")}>
                             <fieldset class={classes!("threshold")}>
-                                <legend title="This mask determines which pixels will be sorted. Pixels brighter than the lower threshold and darker than the upper threshold will be sorted.">{ "Image mask" }</legend>
+                                <legend title="This mask determines which pixels will be sorted. Pixels with a value between the lower and upper threshold will be sorted. Hue wraps around, so a lower threshold above the upper threshold selects across red. Add bands to combine several ranges, or invert the mask to sort everything outside it.">{ "Image mask" }</legend>
+                                { self.view_mask_bands(ctx) }
+                                <div class="custom-radio-group">
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.band().source == MaskSource::Lightness}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Lightness))}
+                                        />
+                                        <span>{"Lightness"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.band().source == MaskSource::Hue}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Hue))}
+                                        />
+                                        <span>{"Hue"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.band().source == MaskSource::Saturation}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Saturation))}
+                                        />
+                                        <span>{"Saturation"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.band().source == MaskSource::Red}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Red))}
+                                        />
+                                        <span>{"Red"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.band().source == MaskSource::Green}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Green))}
+                                        />
+                                        <span>{"Green"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.band().source == MaskSource::Blue}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Blue))}
+                                        />
+                                        <span>{"Blue"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.band().source == MaskSource::Alpha}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Alpha))}
+                                        />
+                                        <span>{"Alpha"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={matches!(self.band().source, MaskSource::Distance(_))}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetMaskSource(MaskSource::Distance([0, 0, 255])))}
+                                        />
+                                        <span>{"Distance to"}</span>
+                                    </label>
+                                    if let MaskSource::Distance(colour) = &self.band().source {
+                                        <input
+                                            type="color"
+                                            value={hex_colour(colour)}
+                                            onchange={ctx.link().batch_callback(|e: Event| {
+                                                parse_hex_colour(&e.target_unchecked_into::<HtmlInputElement>().value()).map(Msg::SetMaskColour)
+                                            })}
+                                        />
+                                    }
+                                </div>
                                 <div class="threshold-grid">
                                     <label for="lower-threshold">{ "Lower threshold: "}</label>
                                     <input
@@ -189,28 +803,72 @@ impl Component for App {
                                         type="range"
                                         min="0"
                                         max="255"
-                                        value={self.sort_settings.lower_threshold.to_string()}
+                                        value={self.band().lower_threshold.to_string()}
                                         oninput={ctx.link().callback(|e: InputEvent| {
                                             Msg::SetLowerThreshold(e.target_unchecked_into::<HtmlInputElement>().value().parse::<u8>().unwrap())
                                         })}
                                         onchange={ctx.link().callback(|_: Event| Msg::SettingsChanged)}
                                     />
-                                    <span>{ self.sort_settings.lower_threshold }</span>
+                                    <span>{ self.threshold_label(self.band().lower_threshold) }</span>
                                     <label for="upper-threshold">{ "Upper threshold: "}</label>
                                     <input
                                         id="upper-threshold"
                                         type="range"
                                         min="0"
                                         max="255"
-                                        value={self.sort_settings.upper_threshold.to_string()}
+                                        value={self.band().upper_threshold.to_string()}
                                         oninput={ctx.link().callback(|e: InputEvent| {
                                             Msg::SetUpperThreshold(e.target_unchecked_into::<HtmlInputElement>().value().parse::<u8>().unwrap())
                                         })}
                                         onchange={ctx.link().callback(|_: Event| Msg::SettingsChanged)}
                                     />
-                                    <span>{ self.sort_settings.upper_threshold }</span>
+                                    <span>{ self.threshold_label(self.band().upper_threshold) }</span>
+                                </div>
+                                <div class="threshold-grid" title="Fade the sorted image out over this many pixels towards the edges of the mask, instead of stopping in a hard seam">
+                                    <label for="feather">{ "Feather: " }</label>
+                                    <input
+                                        id="feather"
+                                        type="range"
+                                        min="0"
+                                        max="64"
+                                        value={self.sort_settings.feather.to_string()}
+                                        oninput={ctx.link().callback(|e: InputEvent| {
+                                            Msg::SetFeather(e.target_unchecked_into::<HtmlInputElement>().value().parse::<u32>().unwrap())
+                                        })}
+                                        onchange={ctx.link().callback(|_: Event| Msg::SettingsChanged)}
+                                    />
+                                    <span>{ format!("{}px", self.sort_settings.feather) }</span>
                                 </div>
                             </fieldset>
+                            <fieldset class={classes!("threshold", "edges")}>
+                                <legend title="Stop spans at outlines found in the image, so streaks don't run across objects. Higher sensitivity finds fainter edges, 0 turns it off.">{ "Edges" }</legend>
+                                <div class="threshold-grid">
+                                    <label for="edge-sensitivity">{ "Sensitivity: " }</label>
+                                    <input
+                                        id="edge-sensitivity"
+                                        type="range"
+                                        min="0"
+                                        max="255"
+                                        value={self.sort_settings.edge_sensitivity.to_string()}
+                                        oninput={ctx.link().callback(|e: InputEvent| {
+                                            Msg::SetEdgeSensitivity(e.target_unchecked_into::<HtmlInputElement>().value().parse::<u8>().unwrap())
+                                        })}
+                                        onchange={ctx.link().callback(|_: Event| Msg::SettingsChanged)}
+                                    />
+                                    <span>{ self.sort_settings.edge_sensitivity }</span>
+                                </div>
+                                <label class="custom-checkbox">
+                                    <div class="box">
+                                        <input
+                                            type="checkbox"
+                                            checked={self.preview == Preview::Edges}
+                                            onchange={ctx.link().callback(|_: Event| Msg::TogglePreview(Preview::Edges))}
+                                        />
+                                        <svg class="checkmark" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24"><path fill="currentColor" d="M20.285 2l-11.285 11.567-5.286-5.011-3.714 3.716 9 8.728 15-15.285z"/></svg>
+                                    </div>
+                                    <span>{ "Preview edges" }</span>
+                                </label>
+                            </fieldset>
                             <fieldset class={classes!("direction")}>
                                 <legend>{ "Sort Direction" }</legend>
                                 <div class="custom-radio-group">
@@ -230,7 +888,50 @@ impl Component for App {
                                         />
                                         <span>{"Vertical"}</span>
                                     </label>
+                                    <label class="custom-radio" title="Follow the outlines in the image for organic, hair-like streaks">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.direction == img::Direction::Contour}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetDirection(img::Direction::Contour))}
+                                        />
+                                        <span>{"Contour"}</span>
+                                    </label>
+                                    <label class="custom-radio" title="Rows bent into waves">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.direction == img::Direction::Wave}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetDirection(img::Direction::Wave))}
+                                        />
+                                        <span>{"Wave"}</span>
+                                    </label>
+                                    <label class="custom-radio" title="One path spiralling in from the edges">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.direction == img::Direction::Spiral}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetDirection(img::Direction::Spiral))}
+                                        />
+                                        <span>{"Spiral"}</span>
+                                    </label>
+                                    <label class="custom-radio" title="One path along a space-filling Hilbert curve">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.direction == img::Direction::Hilbert}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetDirection(img::Direction::Hilbert))}
+                                        />
+                                        <span>{"Hilbert"}</span>
+                                    </label>
+                                    <label class="custom-radio" title="One path in Z-order, for blocky textures">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.direction == img::Direction::ZOrder}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetDirection(img::Direction::ZOrder))}
+                                        />
+                                        <span>{"Z-order"}</span>
+                                    </label>
                                 </div>
+                                if self.sort_settings.direction == img::Direction::Wave {
+                                    { self.view_wave(ctx) }
+                                }
                             </fieldset>
                             <fieldset class={classes!("order")}>
                                 <legend>{ "Sort Order" }</legend>
@@ -253,6 +954,264 @@ impl Component for App {
                                     </label>
                                 </div>
                             </fieldset>
+                            <fieldset class={classes!("sort-key")}>
+                                <legend title="What pixels are sorted by. Brightness is the sum of the channels, the others are measured in the colour space below.">{ "Sort By" }</legend>
+                                <div class="custom-radio-group">
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.sort_key == SortKey::Brightness}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetSortKey(SortKey::Brightness))}
+                                        />
+                                        <span>{"Brightness"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.sort_key == SortKey::Lightness}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetSortKey(SortKey::Lightness))}
+                                        />
+                                        <span>{"Lightness"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.sort_key == SortKey::Chroma}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetSortKey(SortKey::Chroma))}
+                                        />
+                                        <span>{"Chroma"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.sort_key == SortKey::Hue}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetSortKey(SortKey::Hue))}
+                                        />
+                                        <span>{"Hue"}</span>
+                                    </label>
+                                </div>
+                                <span title="Pixels with the same value are then sorted by this">{ "Then by:" }</span>
+                                <div class="custom-radio-group">
+                                    { for [(None, "Nothing"), (Some(SortKey::Brightness), "Brightness"), (Some(SortKey::Lightness), "Lightness"), (Some(SortKey::Chroma), "Chroma"), (Some(SortKey::Hue), "Hue")].into_iter().map(|(key, label)| html! {
+                                        <label class="custom-radio">
+                                            <input
+                                                type="radio"
+                                                checked={self.sort_settings.secondary_key == key}
+                                                onchange={ctx.link().callback(move |_: Event| Msg::SetSecondaryKey(key))}
+                                            />
+                                            <span>{label}</span>
+                                        </label>
+                                    }) }
+                                </div>
+                                <label class="custom-checkbox" title="Pixels that still tie keep their original order, so the result never depends on the sorting algorithm">
+                                    <div class="box">
+                                        <input
+                                            type="checkbox"
+                                            checked={self.sort_settings.stable_sort}
+                                            onchange={ctx.link().callback(|_: Event| Msg::ToggleStableSort)}
+                                        />
+                                        <svg class="checkmark" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24"><path fill="currentColor" d="M20.285 2l-11.285 11.567-5.286-5.011-3.714 3.716 9 8.728 15-15.285z"/></svg>
+                                    </div>
+                                    <span>{ "Stable sort" }</span>
+                                </label>
+                            </fieldset>
+                            <fieldset class={classes!("colour-space")}>
+                                <legend title="Colour space for lightness, chroma and hue, including the lightness used by the image mask. OKLab and CIELAB are perceptually even, so they give smoother gradients.">{ "Colour Space" }</legend>
+                                <div class="custom-radio-group">
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.colour_space == ColourSpace::Rgb}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetColourSpace(ColourSpace::Rgb))}
+                                        />
+                                        <span>{"RGB"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.colour_space == ColourSpace::OkLab}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetColourSpace(ColourSpace::OkLab))}
+                                        />
+                                        <span>{"OKLab"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.colour_space == ColourSpace::CieLab}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetColourSpace(ColourSpace::CieLab))}
+                                        />
+                                        <span>{"CIELAB"}</span>
+                                    </label>
+                                </div>
+                            </fieldset>
+                            <fieldset class={classes!("alpha-mode")}>
+                                <legend title="How transparent pixels are sorted. Skip transparent leaves fully transparent pixels in place and stops spans at them. Premultiplied sorts by colour weighted by opacity. Fixed alpha keeps the transparency where it is and only moves colour.">{ "Transparency" }</legend>
+                                <div class="custom-radio-group">
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.alpha_mode == AlphaMode::Included}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetAlphaMode(AlphaMode::Included))}
+                                        />
+                                        <span>{"Included"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.alpha_mode == AlphaMode::SkipTransparent}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetAlphaMode(AlphaMode::SkipTransparent))}
+                                        />
+                                        <span>{"Skip transparent"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.alpha_mode == AlphaMode::Premultiplied}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetAlphaMode(AlphaMode::Premultiplied))}
+                                        />
+                                        <span>{"Premultiplied"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.alpha_mode == AlphaMode::FixedAlpha}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetAlphaMode(AlphaMode::FixedAlpha))}
+                                        />
+                                        <span>{"Fixed alpha"}</span>
+                                    </label>
+                                </div>
+                            </fieldset>
+                            <fieldset class={classes!("threshold", "blend")}>
+                                <legend title="Mix the sorted image back into the original to tone the effect down. The blend mode combines the two the same way as layers in an image editor.">{ "Blend" }</legend>
+                                <div class="custom-radio-group">
+                                    { for [
+                                        (BlendMode::Normal, "Normal"),
+                                        (BlendMode::Multiply, "Multiply"),
+                                        (BlendMode::Screen, "Screen"),
+                                        (BlendMode::Overlay, "Overlay"),
+                                        (BlendMode::Difference, "Difference"),
+                                        (BlendMode::Lighten, "Lighten"),
+                                        (BlendMode::Darken, "Darken"),
+                                    ].into_iter().map(|(mode, label)| html! {
+                                        <label class="custom-radio">
+                                            <input
+                                                type="radio"
+                                                checked={self.sort_settings.blend.mode == mode}
+                                                onchange={ctx.link().callback(move |_: Event| Msg::SetBlendMode(mode.clone()))}
+                                            />
+                                            <span>{label}</span>
+                                        </label>
+                                    }) }
+                                </div>
+                                <div class="threshold-grid">
+                                    <label for="blend-opacity">{ "Opacity: " }</label>
+                                    <input
+                                        id="blend-opacity"
+                                        type="range"
+                                        min="0"
+                                        max="100"
+                                        value={self.sort_settings.blend.opacity.to_string()}
+                                        oninput={ctx.link().callback(|e: InputEvent| {
+                                            Msg::SetBlendOpacity(e.target_unchecked_into::<HtmlInputElement>().value().parse::<u8>().unwrap())
+                                        })}
+                                        onchange={ctx.link().callback(|_: Event| Msg::SettingsChanged)}
+                                    />
+                                    <span>{ format!("{}%", self.sort_settings.blend.opacity) }</span>
+                                </div>
+                            </fieldset>
+                            <fieldset class={classes!("span-length")}>
+                                <legend title="Spans shorter than the minimum are left unsorted and spans longer than the maximum are cut up, either every maximum length or at random lengths in between. Leave at 0 for no limit.">{ "Span Length" }</legend>
+                                <div class="span-length-grid">
+                                    <label for="min-span-length">{ "Minimum: " }</label>
+                                    <input
+                                        id="min-span-length"
+                                        type="number"
+                                        min="0"
+                                        value={self.sort_settings.min_span_length.to_string()}
+                                        onchange={ctx.link().callback(|e: Event| {
+                                            Msg::SetMinSpanLength(e.target_unchecked_into::<HtmlInputElement>().value_as_number() as u32)
+                                        })}
+                                    />
+                                    <label for="max-span-length">{ "Maximum: " }</label>
+                                    <input
+                                        id="max-span-length"
+                                        type="number"
+                                        min="0"
+                                        value={self.sort_settings.max_span_length.to_string()}
+                                        onchange={ctx.link().callback(|e: Event| {
+                                            Msg::SetMaxSpanLength(e.target_unchecked_into::<HtmlInputElement>().value_as_number() as u32)
+                                        })}
+                                    />
+                                </div>
+                                <div class="custom-radio-group">
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.span_split == SpanSplit::Fixed}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetSpanSplit(SpanSplit::Fixed))}
+                                        />
+                                        <span>{"Fixed split"}</span>
+                                    </label>
+                                    <label class="custom-radio">
+                                        <input
+                                            type="radio"
+                                            checked={self.sort_settings.span_split == SpanSplit::Random}
+                                            onchange={ctx.link().callback(|_: Event| Msg::SetSpanSplit(SpanSplit::Random))}
+                                        />
+                                        <span>{"Random split"}</span>
+                                    </label>
+                                </div>
+                            </fieldset>
+                            <fieldset class={classes!("seed")}>
+                                <legend title="Everything random, like random span splitting, comes from this number. The same seed always gives the same image.">{ "Seed" }</legend>
+                                <div class="seed-row">
+                                    <input
+                                        type="number"
+                                        min="0"
+                                        step="1"
+                                        value={self.sort_settings.seed.to_string()}
+                                        onchange={ctx.link().callback(|e: Event| {
+                                            Msg::SetSeed(e.target_unchecked_into::<HtmlInputElement>().value().parse().unwrap_or_default())
+                                        })}
+                                    />
+                                    <button
+                                        class="btn"
+                                        disabled={self.worker_status.is_some()}
+                                        onclick={ctx.link().callback(|_| Msg::RerollSeed)}
+                                    >
+                                        <Icon icon_id={IconId::LucideDices} />
+                                        { "Reroll" }
+                                    </button>
+                                </div>
+                            </fieldset>
+                            if self.img.is_some() {
+                                <fieldset class={classes!("regions")}>
+                                    <legend title="Only sort inside rectangles dragged out on the image, leaving everything else as it was.">{ "Regions" }</legend>
+                                    <div class="button-row">
+                                        <label class="custom-checkbox mr-auto">
+                                            <div class="box">
+                                                <input
+                                                    type="checkbox"
+                                                    checked={self.selecting_regions}
+                                                    onchange={ctx.link().callback(|_: Event| Msg::ToggleSelectRegions)}
+                                                />
+                                                <svg class="checkmark" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24"><path fill="currentColor" d="M20.285 2l-11.285 11.567-5.286-5.011-3.714 3.716 9 8.728 15-15.285z"/></svg>
+                                            </div>
+                                            <span>{ "Draw regions" }</span>
+                                        </label>
+                                        <button
+                                            class="btn"
+                                            disabled={self.sort_settings.regions.is_empty()}
+                                            onclick={ctx.link().callback(|_| Msg::ClearRegions)}
+                                        >
+                                            { match self.sort_settings.regions.len() {
+                                                0 => "No regions".to_string(),
+                                                n => format!("Clear {} region{}", n, if n == 1 { "" } else { "s" }),
+                                            } }
+                                        </button>
+                                    </div>
+                                </fieldset>
+                            }
                             <div class="button-row">
                                 <button
                                     class="btn mr-auto"
@@ -265,17 +1224,40 @@ impl Component for App {
                             </div>
                             if let Some(img) = &self.img {
                                 <div class="button-row">
-                                    <label class="custom-checkbox">
-                                        <div class="box">
+                                    <div class="custom-radio-group mr-auto">
+                                        <label class="custom-radio">
                                             <input
-                                                type="checkbox"
-                                                checked={self.show_original}
-                                                onchange={ctx.link().callback(|_: Event| Msg::ToggleShowOriginal)}
+                                                type="radio"
+                                                checked={self.view == View::Sorted}
+                                                onchange={ctx.link().callback(|_: Event| Msg::SetView(View::Sorted))}
                                             />
-                                            <svg class="checkmark" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24"><path fill="currentColor" d="M20.285 2l-11.285 11.567-5.286-5.011-3.714 3.716 9 8.728 15-15.285z"/></svg>
-                                        </div>
-                                        <span>{ "Show original" }</span>
-                                    </label>
+                                            <span>{"Sorted"}</span>
+                                        </label>
+                                        <label class="custom-radio">
+                                            <input
+                                                type="radio"
+                                                checked={self.view == View::Original}
+                                                onchange={ctx.link().callback(|_: Event| Msg::SetView(View::Original))}
+                                            />
+                                            <span>{"Original"}</span>
+                                        </label>
+                                        <label class="custom-radio">
+                                            <input
+                                                type="radio"
+                                                checked={self.view == View::Split}
+                                                onchange={ctx.link().callback(|_: Event| Msg::SetView(View::Split))}
+                                            />
+                                            <span>{"Split"}</span>
+                                        </label>
+                                        <label class="custom-radio">
+                                            <input
+                                                type="radio"
+                                                checked={self.view == View::SideBySide}
+                                                onchange={ctx.link().callback(|_: Event| Msg::SetView(View::SideBySide))}
+                                            />
+                                            <span>{"Side by side"}</span>
+                                        </label>
+                                    </div>
                                     <button
                                         class="btn"
                                         disabled={self.worker_status.is_some()}
@@ -283,19 +1265,36 @@ impl Component for App {
                                     >
                                         { "Clear Image" }
                                     </button>
-                                    if let (Some(sorted_data), true) = (&self.sorted_data, self.worker_status.is_none()) {
-                                        { self.download_button(sorted_data, img.name.clone()) }
+                                    if self.worker_status.is_none() {
+                                        if let Some((sorted, url)) = &self.sorted_data {
+                                            { self.download_button(sorted, url, img.name.clone()) }
+                                        } else if self.sorted_pixels.is_some() {
+                                            <button class="btn" onclick={ctx.link().callback(|_| Msg::SavePreview)}>
+                                                <Icon icon_id={IconId::LucideDownload} />
+                                                { "Save" }
+                                            </button>
+                                        }
                                     }
                                 </div>
                             }
+                            if self.img.is_some() {
+                                { self.view_animation(ctx) }
+                            }
+                            if !self.batch.is_empty() {
+                                { self.view_batch(ctx) }
+                            }
                         </div>
                     </div>
                     <div
                         class={classes!("output", "overlay-container", self.img.is_some().then_some(Some("has-image")))}
-                        ondrop={ctx.link().callback(|event: DragEvent| {
+                        ondrop={ctx.link().batch_callback(|event: DragEvent| {
                             event.prevent_default();
-                            let files = event.data_transfer().unwrap().files();
-                            Self::load_image(files)
+                            let data = event.data_transfer()?;
+                            match data.files() {
+                                Some(files) if files.length() > 0 => Some(Self::load_image(Some(files))),
+                                // Images dragged from another tab only come with their URL
+                                _ => data.get_data("text/uri-list").ok().as_deref().and_then(image_url).map(Msg::LoadImageUrl),
+                            }
                         })}
                         ondragover={Callback::from(|event: DragEvent| {
                             event.prevent_default();
@@ -312,12 +1311,13 @@ impl Component for App {
                                 class={classes!("placeholder", "drop-container")}
                             >
                                 <Icon icon_id={IconId::LucideImagePlus} />
-                                <p>{"Drop your image here or click to select"}</p>
+                                <p>{"Drop or paste your image here, or click to select"}</p>
                                 <input
                                     id="file-upload"
                                     class="sr-only"
                                     type="file"
                                     accept="image/*"
+                                    multiple=true
                                     onchange={ctx.link().callback(move |e: Event| {
                                         let input: HtmlInputElement = e.target_unchecked_into();
                                         Self::load_image(input.files())
@@ -339,42 +1339,106 @@ impl Component for App {
 
 impl App {
     fn view_img(&self, ctx: &Context<Self>, img: &ImageDetails) -> Html {
-        let (data, file_type) =
-            if let (Some(sorted), false) = (&self.sorted_data, self.show_original) {
-                // Sorted image is always jpeg (png encoding is really slow)
-                (sorted, "image/jpeg".to_string())
-            } else {
-                (&img.data, img.file_type.clone())
-            };
+        let original_src = img.url.to_string();
+        // Image sequences can't be previewed, so show the original
+        let sorted_src = match (&self.sorted_pixels, &self.sorted_data) {
+            (Some(pixels), _) => Some(ImageSource::Pixels(pixels.clone())),
+            (_, Some((sorted, url))) if sorted.format.is_image() => {
+                Some(ImageSource::Url(url.to_string()))
+            }
+            _ => None,
+        };
+        let shown = match (&sorted_src, self.view) {
+            (Some(sorted), View::Sorted | View::Split | View::SideBySide) => sorted.clone(),
+            _ => ImageSource::Url(original_src.clone()),
+        };
+        let dimensions = img.dimensions.or(self
+            .sorted_pixels
+            .as_ref()
+            .map(|pixels| (pixels.width, pixels.height)));
 
-        let data_str = format!("data:{};base64,{}", file_type, b64.encode(data.as_slice()));
+        let zoom = if self.selecting_regions {
+            Callback::default()
+        } else {
+            ctx.link().callback(|_| Msg::ToggleZoom)
+        };
+        let image = shown.view(img.name.clone(), zoom);
+
+        let content = match (&sorted_src, dimensions) {
+            (Some(sorted), _) if matches!(self.view, View::Split | View::SideBySide) => html! {
+                <Comparison
+                    name={img.name.clone()}
+                    original={original_src.clone()}
+                    sorted={sorted.clone()}
+                    side_by_side={self.view == View::SideBySide}
+                    ondblclick={ctx.link().callback(|_| Msg::ToggleZoom)}
+                />
+            },
+            (_, Some((w, h))) if self.selecting_regions => {
+                // The image fills the frame, so offsets into it convert straight to image coordinates
+                let to_image = move |e: &MouseEvent| {
+                    let target: Element = e.target_unchecked_into();
+                    let scale = |offset: i32, size: i32, max: u32| {
+                        (offset as f64 / size.max(1) as f64 * max as f64).clamp(0.0, max as f64)
+                            as u32
+                    };
+                    (
+                        scale(e.offset_x(), target.client_width(), w),
+                        scale(e.offset_y(), target.client_height(), h),
+                    )
+                };
+                html! {
+                    <div
+                        class="image-frame selecting"
+                        style={format!("--width: {}px; --ratio: {};", w, w as f64 / h as f64)}
+                        onmousedown={ctx.link().callback(move |e: MouseEvent| {
+                            // Stop the browser dragging the image itself
+                            e.prevent_default();
+                            Msg::StartRegion(to_image(&e))
+                        })}
+                        onmousemove={ctx.link().batch_callback(move |e: MouseEvent| {
+                            (e.buttons() == 1).then(|| Msg::DragRegion(to_image(&e)))
+                        })}
+                        onmouseup={ctx.link().callback(|_| Msg::EndRegion)}
+                        onmouseleave={ctx.link().callback(|_| Msg::EndRegion)}
+                    >
+                        { image }
+                        { self.view_regions((w, h)) }
+                    </div>
+                }
+            }
+            (_, Some((w, h))) => html! {
+                <div
+                    class="image-frame"
+                    style={format!("--width: {}px; --ratio: {};", w, w as f64 / h as f64)}
+                >
+                    { image }
+                    { self.view_regions((w, h)) }
+                </div>
+            },
+            _ => image,
+        };
 
         html! {
             <>
-                <img
-                    onclick={ctx.link().callback(move |_| Msg::ToggleZoom)}
-                    src={data_str.clone()}
-                    alt={img.name.clone()}
-                />
+                { content }
                 if self.worker_status.is_some() {
                     <div class={classes!("overlay")}>
                         <div class={classes!("content")}>
                             <Icon icon_id={IconId::LucideLoader} />
                             if let Some(status) = &self.worker_status {
-                                {match status {
-                                    WorkerStatus::Decoding => {"Decoding image"},
-                                    WorkerStatus::Sorting => {"Sorting the pixels"},
-                                    WorkerStatus::Encoding => {"Encoding the image"},
-                                }}
+                                { status_text(status) }
                             }
                         </div>
                     </div>
                 }
                 if self.zoomed {
                     <FullscreenImage
-                        data={data.clone()}
+                        src={shown}
                         name={img.name.clone()}
-                        file_type={file_type}
+                        original={original_src}
+                        view={self.view}
+                        dimensions={img.dimensions}
                         onclose={ctx.link().callback(|_| Msg::ToggleZoom)}
                     />
                 }
@@ -382,22 +1446,328 @@ impl App {
         }
     }
 
-    fn download_button(&self, data: &Vec<u8>, original_name: String) -> Html {
-        let data_str = format!("data:image/jpeg;base64,{}", b64.encode(data.as_slice()));
-        let download_filename = format!(
-            "{}_sorted.jpg",
-            PathBuf::from(original_name)
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-        );
+    fn view_regions(&self, (w, h): (u32, u32)) -> Html {
+        html! {
+            { for self.sort_settings.regions.iter().chain(self.region_drag.map(|(a, b)| region_between(a, b)).as_ref()).map(|region| html! {
+                <div
+                    class="region"
+                    style={format!(
+                        "left: {}%; top: {}%; width: {}%; height: {}%;",
+                        region.x as f64 / w as f64 * 100.0,
+                        region.y as f64 / h as f64 * 100.0,
+                        region.width as f64 / w as f64 * 100.0,
+                        region.height as f64 / h as f64 * 100.0,
+                    )}
+                />
+            }) }
+        }
+    }
+
+    fn view_wave(&self, ctx: &Context<Self>) -> Html {
+        let wave = &self.sort_settings.wave;
+
+        html! {
+            <>
+                <div class="custom-radio-group">
+                    <label class="custom-radio">
+                        <input
+                            type="radio"
+                            checked={wave.shape == WaveShape::Sine}
+                            onchange={ctx.link().callback(|_: Event| Msg::SetWaveShape(WaveShape::Sine))}
+                        />
+                        <span>{"Sine"}</span>
+                    </label>
+                    <label class="custom-radio">
+                        <input
+                            type="radio"
+                            checked={wave.shape == WaveShape::ZigZag}
+                            onchange={ctx.link().callback(|_: Event| Msg::SetWaveShape(WaveShape::ZigZag))}
+                        />
+                        <span>{"Zig-zag"}</span>
+                    </label>
+                    <label class="custom-radio">
+                        <input
+                            type="radio"
+                            checked={wave.shape == WaveShape::Bezier}
+                            onchange={ctx.link().callback(|_: Event| Msg::SetWaveShape(WaveShape::Bezier))}
+                        />
+                        <span>{"Bezier"}</span>
+                    </label>
+                </div>
+                <div class="wave-grid">
+                    <label for="wave-amplitude">{ "Amplitude: " }</label>
+                    <input
+                        id="wave-amplitude"
+                        type="number"
+                        min="0"
+                        max={MAX_WAVE_AMPLITUDE.to_string()}
+                        value={wave.amplitude.to_string()}
+                        onchange={ctx.link().callback(|e: Event| {
+                            Msg::SetWaveAmplitude(e.target_unchecked_into::<HtmlInputElement>().value_as_number() as u32)
+                        })}
+                    />
+                    <label for="wavelength">{ "Wavelength: " }</label>
+                    <input
+                        id="wavelength"
+                        type="number"
+                        min="1"
+                        max={MAX_WAVELENGTH.to_string()}
+                        value={wave.wavelength.to_string()}
+                        onchange={ctx.link().callback(|e: Event| {
+                            Msg::SetWavelength(e.target_unchecked_into::<HtmlInputElement>().value_as_number() as u32)
+                        })}
+                    />
+                    <label for="wave-phase">{ "Phase: " }</label>
+                    <input
+                        id="wave-phase"
+                        type="range"
+                        min="0"
+                        max="359"
+                        value={wave.phase.to_string()}
+                        oninput={ctx.link().callback(|e: InputEvent| {
+                            Msg::SetWavePhase(e.target_unchecked_into::<HtmlInputElement>().value().parse::<u16>().unwrap())
+                        })}
+                        onchange={ctx.link().callback(|_: Event| Msg::SettingsChanged)}
+                    />
+                    <span>{ format!("{}°", wave.phase) }</span>
+                </div>
+            </>
+        }
+    }
+
+    fn band(&self) -> &MaskBand {
+        &self.sort_settings.mask.bands[self.selected_band]
+    }
+
+    fn threshold_label(&self, value: u8) -> String {
+        match self.band().source {
+            MaskSource::Hue => format!("{}°", value as u32 * 360 / 255),
+            _ => value.to_string(),
+        }
+    }
+
+    fn view_mask_bands(&self, ctx: &Context<Self>) -> Html {
+        let mask = &self.sort_settings.mask;
 
+        html! {
+            <>
+                <div class="band-tabs">
+                    { for (0..mask.bands.len()).map(|i| html! {
+                        <button
+                            class={classes!("btn", (i == self.selected_band).then_some("selected"))}
+                            onclick={ctx.link().callback(move |_| Msg::SelectMaskBand(i))}
+                        >
+                            { format!("Band {}", i + 1) }
+                        </button>
+                    }) }
+                    <button class="btn" title="Add band" onclick={ctx.link().callback(|_| Msg::AddMaskBand)}>
+                        { "+" }
+                    </button>
+                    if mask.bands.len() > 1 {
+                        <button class="btn" onclick={ctx.link().callback(|_| Msg::RemoveMaskBand)}>
+                            { "Remove" }
+                        </button>
+                    }
+                </div>
+                <div class="custom-radio-group">
+                    if mask.bands.len() > 1 {
+                        <label class="custom-radio">
+                            <input
+                                type="radio"
+                                checked={mask.combine == MaskCombine::Union}
+                                onchange={ctx.link().callback(|_: Event| Msg::SetMaskCombine(MaskCombine::Union))}
+                            />
+                            <span>{"Any band"}</span>
+                        </label>
+                        <label class="custom-radio">
+                            <input
+                                type="radio"
+                                checked={mask.combine == MaskCombine::Intersection}
+                                onchange={ctx.link().callback(|_: Event| Msg::SetMaskCombine(MaskCombine::Intersection))}
+                            />
+                            <span>{"Every band"}</span>
+                        </label>
+                    }
+                    <label class="custom-checkbox">
+                        <div class="box">
+                            <input
+                                type="checkbox"
+                                checked={mask.invert}
+                                onchange={ctx.link().callback(|_: Event| Msg::ToggleMaskInvert)}
+                            />
+                            <svg class="checkmark" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24"><path fill="currentColor" d="M20.285 2l-11.285 11.567-5.286-5.011-3.714 3.716 9 8.728 15-15.285z"/></svg>
+                        </div>
+                        <span>{ "Invert" }</span>
+                    </label>
+                    <label class="custom-checkbox">
+                        <div class="box">
+                            <input
+                                type="checkbox"
+                                checked={self.preview == Preview::Mask}
+                                onchange={ctx.link().callback(|_: Event| Msg::TogglePreview(Preview::Mask))}
+                            />
+                            <svg class="checkmark" xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 24"><path fill="currentColor" d="M20.285 2l-11.285 11.567-5.286-5.011-3.714 3.716 9 8.728 15-15.285z"/></svg>
+                        </div>
+                        <span>{ "Preview mask" }</span>
+                    </label>
+                </div>
+            </>
+        }
+    }
+
+    fn view_animation(&self, ctx: &Context<Self>) -> Html {
+        let format_radio = |format: AnimationFormat, label: &'static str| {
+            html! {
+                <label class="custom-radio">
+                    <input
+                        type="radio"
+                        checked={self.animation.format == format}
+                        onchange={ctx.link().callback(move |_: Event| Msg::SetAnimationFormat(format.clone()))}
+                    />
+                    <span>{ label }</span>
+                </label>
+            }
+        };
+
+        html! {
+            <fieldset class={classes!("animation")}>
+                <legend title="Animate the settings by adding keyframes. Settings between keyframes are interpolated. Animated images keep their own frames and timing.">{ "Animation" }</legend>
+                <div class="animation-grid">
+                    <label for="frame-count">{ "Frames: " }</label>
+                    <input
+                        id="frame-count"
+                        type="number"
+                        min="2"
+                        max="600"
+                        value={self.animation.frame_count.to_string()}
+                        onchange={ctx.link().callback(|e: Event| {
+                            Msg::SetFrameCount(e.target_unchecked_into::<HtmlInputElement>().value_as_number() as u32)
+                        })}
+                    />
+                    <label for="frame-delay">{ "Frame delay (ms): " }</label>
+                    <input
+                        id="frame-delay"
+                        type="number"
+                        min="10"
+                        max="10000"
+                        step="10"
+                        value={self.animation.frame_delay_ms.to_string()}
+                        onchange={ctx.link().callback(|e: Event| {
+                            Msg::SetFrameDelay(e.target_unchecked_into::<HtmlInputElement>().value_as_number() as u32)
+                        })}
+                    />
+                    <label for="keyframe-frame">{ "Keyframe at frame: " }</label>
+                    <input
+                        id="keyframe-frame"
+                        type="number"
+                        min="1"
+                        max={self.animation.frame_count.to_string()}
+                        value={self.keyframe_frame.to_string()}
+                        onchange={ctx.link().callback(|e: Event| {
+                            Msg::SetKeyframeFrame(e.target_unchecked_into::<HtmlInputElement>().value_as_number() as u32)
+                        })}
+                    />
+                </div>
+                <ul class="keyframe-list">
+                    { for self.animation.keyframes.iter().enumerate().map(|(i, keyframe)| html! {
+                        <li class="keyframe">
+                            <span>
+                                { format!(
+                                    "Frame {}: {}–{}",
+                                    keyframe.frame,
+                                    keyframe.settings.mask.bands[0].lower_threshold,
+                                    keyframe.settings.mask.bands[0].upper_threshold,
+                                ) }
+                            </span>
+                            <button class="btn" onclick={ctx.link().callback(move |_| Msg::RemoveKeyframe(i))}>
+                                { "Remove" }
+                            </button>
+                        </li>
+                    }) }
+                </ul>
+                <div class="custom-radio-group">
+                    { format_radio(AnimationFormat::Gif, "GIF") }
+                    { format_radio(AnimationFormat::Apng, "APNG") }
+                    { format_radio(AnimationFormat::PngSequence, "PNG sequence") }
+                </div>
+                <div class="button-row">
+                    <button
+                        class="btn"
+                        onclick={ctx.link().callback(|_| Msg::AddKeyframe)}
+                    >
+                        { "Add keyframe" }
+                    </button>
+                    <button
+                        class="btn"
+                        disabled={self.worker_status.is_some() || self.animation.keyframes.is_empty()}
+                        onclick={ctx.link().callback(|_| Msg::RenderAnimation)}
+                    >
+                        { "Render animation" }
+                    </button>
+                </div>
+            </fieldset>
+        }
+    }
+
+    fn view_batch(&self, ctx: &Context<Self>) -> Html {
+        let busy = self
+            .batch
+            .iter()
+            .any(|item| matches!(item.status, BatchStatus::Queued | BatchStatus::Processing));
+        let done = self
+            .batch
+            .iter()
+            .filter(|item| matches!(item.status, BatchStatus::Done(..)))
+            .count();
+
+        html! {
+            <fieldset class={classes!("batch")}>
+                <legend>{ format!("Batch ({}/{})", done, self.batch.len()) }</legend>
+                <ul class="batch-list">
+                    { for self.batch.iter().map(|item| html! {
+                        <li class="batch-item">
+                            <span class="name" title={item.name.clone()}>{ &item.name }</span>
+                            {match &item.status {
+                                BatchStatus::Reading => html! { <span class="status">{ "Reading" }</span> },
+                                BatchStatus::Queued => html! { <span class="status">{ "Queued" }</span> },
+                                BatchStatus::Processing => html! {
+                                    <span class="status"><Icon icon_id={IconId::LucideLoader} />{ "Sorting" }</span>
+                                },
+                                BatchStatus::Done(sorted, url) => self.download_button(sorted, url, item.name.clone()),
+                                BatchStatus::Failed(err) => html! {
+                                    <span class="status failed" title={err.clone()}>{ "Failed" }</span>
+                                },
+                            }}
+                        </li>
+                    }) }
+                </ul>
+                <div class="button-row">
+                    <button
+                        class="btn"
+                        disabled={busy}
+                        onclick={ctx.link().callback(|_| Msg::RunBatch)}
+                    >
+                        { "Sort all" }
+                    </button>
+                    <button
+                        class="btn"
+                        disabled={busy || done == 0}
+                        onclick={ctx.link().callback(|_| Msg::SaveAll)}
+                    >
+                        <Icon icon_id={IconId::LucideDownload} />
+                        { "Save all" }
+                    </button>
+                </div>
+            </fieldset>
+        }
+    }
+
+    fn download_button(&self, sorted: &SortedImage, url: &BlobUrl, original_name: String) -> Html {
         html! {
             <a
                 class="btn"
-                download={download_filename}
-                href={data_str}
+                download={sorted_file_name(&original_name, sorted.format)}
+                href={url.to_string()}
             >
                 <Icon icon_id={IconId::LucideDownload} />
                 { "Save" }
@@ -405,17 +1775,110 @@ impl App {
         }
     }
 
+    /// Start a download of `url`, keeping it alive until the next one
+    fn download(&mut self, url: BlobUrl, file_name: &str) {
+        let link: HtmlAnchorElement = gloo::utils::document()
+            .create_element("a")
+            .unwrap()
+            .unchecked_into();
+        link.set_href(&url);
+        link.set_download(file_name);
+        link.click();
+        self.download_url = Some(url);
+    }
+
     fn load_image(files: Option<FileList>) -> Msg {
         if let Some(files) = files {
-            let file = js_sys::try_iter(&files)
+            let mut files = js_sys::try_iter(&files)
                 .unwrap()
                 .unwrap()
                 .map(|v| web_sys::File::from(v.unwrap()))
                 .map(File::from)
-                .next();
-            Msg::LoadImage(file)
+                .collect::<Vec<_>>();
+            if files.len() > 1 {
+                Msg::LoadImages(files)
+            } else {
+                Msg::LoadImage(files.pop())
+            }
         } else {
             Msg::LoadImage(None)
         }
     }
 }
+
+fn status_text(status: &WorkerStatus) -> String {
+    match status {
+        WorkerStatus::Decoding => "Decoding image".to_string(),
+        WorkerStatus::Sorting => "Sorting the pixels".to_string(),
+        WorkerStatus::SortingFrame(frame, total) => {
+            format!("Sorting frame {} of {}", frame, total)
+        }
+        WorkerStatus::Encoding => "Encoding the image".to_string(),
+    }
+}
+
+fn hex_colour([r, g, b]: &[u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn parse_hex_colour(hex: &str) -> Option<[u8; 3]> {
+    let hex = hex.strip_prefix('#')?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// The first URL in a `text/uri-list` that can be fetched from here without a CORS request
+fn image_url(uri_list: &str) -> Option<String> {
+    uri_list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find(|url| url.starts_with("blob:") || url.starts_with("data:image/"))
+        .map(str::to_string)
+}
+
+async fn fetch_blob(url: &str) -> Result<Blob, JsValue> {
+    let response: Response = JsFuture::from(gloo::utils::window().fetch_with_str(url))
+        .await?
+        .dyn_into()?;
+    let blob: web_sys::Blob = JsFuture::from(response.blob()?).await?.dyn_into()?;
+    Ok(Blob::from(blob))
+}
+
+fn region_between((x1, y1): (u32, u32), (x2, y2): (u32, u32)) -> Region {
+    Region {
+        x: x1.min(x2),
+        y: y1.min(y2),
+        width: x1.abs_diff(x2),
+        height: y1.abs_diff(y2),
+    }
+}
+
+/// The settings are kept in the URL fragment, so a link reproduces the same image
+fn settings_from_url() -> Option<SortSettings> {
+    let hash = gloo::utils::window().location().hash().ok()?;
+    let json = b64url.decode(hash.strip_prefix('#')?).ok()?;
+    serde_json::from_slice(&json)
+        .ok()
+        .filter(SortSettings::is_valid)
+}
+
+fn save_settings_to_url(settings: &SortSettings) {
+    let hash = format!("#{}", b64url.encode(serde_json::to_vec(settings).unwrap()));
+    gloo::utils::history()
+        .replace_state_with_url(&JsValue::NULL, "", Some(&hash))
+        .ok();
+}
+
+/// The original name with the extension of the format the sorted image was encoded as
+fn sorted_file_name(original_name: &str, format: OutputFormat) -> String {
+    format!(
+        "{}_sorted.{}",
+        PathBuf::from(original_name)
+            .file_stem()
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        format.extension()
+    )
+}
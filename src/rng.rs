@@ -0,0 +1,59 @@
+use std::ops::Range;
+
+const GOLDEN_GAMMA: u64 = 0x9E3779B97F4A7C15;
+
+/// Small deterministic random number generator (SplitMix64). It's implemented here rather than
+/// pulled in so that a seed gives exactly the same image in the browser and natively.
+pub struct Rng(u64);
+
+impl Rng {
+    /// Independent generator for one line (row or column) of an image, so each line's randomness
+    /// doesn't depend on how many numbers the lines before it used
+    pub fn for_line(seed: u64, line: u32) -> Self {
+        let mut rng = Rng(seed ^ (line as u64 + 1).wrapping_mul(GOLDEN_GAMMA));
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(GOLDEN_GAMMA);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed number in `range`, which must not be empty
+    pub fn range(&mut self, range: Range<usize>) -> usize {
+        range.start + (self.next_u64() % (range.end - range.start) as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_and_line_repeat() {
+        let mut a = Rng::for_line(42, 7);
+        let mut b = Rng::for_line(42, 7);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn lines_and_seeds_differ() {
+        let first = |seed, line| Rng::for_line(seed, line).next_u64();
+        assert_ne!(first(42, 0), first(42, 1));
+        assert_ne!(first(42, 0), first(43, 0));
+    }
+
+    #[test]
+    fn range_stays_in_bounds() {
+        let mut rng = Rng::for_line(0, 0);
+        for _ in 0..1000 {
+            assert!((5..9).contains(&rng.range(5..9)));
+        }
+    }
+}